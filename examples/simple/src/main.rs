@@ -12,9 +12,10 @@ fn main() {
     peek();
 }
 
+#[allow(dead_code)]
 fn pop() {
     fs::create_dir_all(PathBuf::from("/tmp/bigqueue")).expect("create dir error");
-    let mut q = BigQueue::new(&"/tmp/bigqueue", true).unwrap();
+    let mut q = BigQueue::new("/tmp/bigqueue", true).unwrap();
 
     let start = PreciseTime::now();
     let total = 100000000;
@@ -32,7 +33,7 @@ fn pop() {
     loop {
         let pop_data = q.pop();
         if  pop_data.is_ok() && pop_data.unwrap().len() == data.len() {
-            count = count + 1;
+            count += 1;
         } else {
             println!("count {}", count);
             break;
@@ -43,9 +44,10 @@ fn pop() {
 
 }
 
+#[allow(dead_code)]
 fn dequeue() {
     fs::create_dir_all(PathBuf::from("/tmp/bigqueue")).expect("create dir error");
-    let mut q = BigQueue::new(&"/tmp/bigqueue", true).unwrap();
+    let mut q = BigQueue::new("/tmp/bigqueue", true).unwrap();
 
     let start = PreciseTime::now();
     let total = 100000000;
@@ -60,7 +62,7 @@ fn dequeue() {
     let start = PreciseTime::now();
     loop {
         if q.dequeue().is_ok() {
-            count = count + 1;
+            count += 1;
         } else {
             println!("count {}", count);
             break;
@@ -72,17 +74,15 @@ fn dequeue() {
 
 fn peek() {
     fs::create_dir_all(PathBuf::from("/tmp/bigqueue")).expect("create dir error");
-    let mut q = BigQueue::new(&"/tmp/bigqueue", true).unwrap();
+    let mut q = BigQueue::new("/tmp/bigqueue", true).unwrap();
     let data = b"1234567890abcdefghij";
     q.push(data).expect("push error");
 
     let mut count = 0;
 
     loop {
-        let pk = q.peek();
-        if pk.is_ok() {
-            let b = pk.unwrap();
-            count = count + 1;
+        if q.peek().is_ok() {
+            count += 1;
 
             if count == 5 {
                 println!("peek {}", count);
@@ -23,17 +23,10 @@ fn main() {
 
         let start = PreciseTime::now();
         let mut count = 0;
-        loop{
-            if rx.dequeue().is_ok() {
-                count = count + 1;
-
-            }
-            if count == total {
-                println!("count {}", count);
-                break;
-            }
-
+        while rx.recv().is_ok() {
+            count += 1;
         }
+        println!("count {}", count);
         let end = PreciseTime::now();
         println!("{} seconds for enqueue and dequeue. {} ps", start.to(end), total*1000000/start.to(end).num_microseconds().unwrap());
         t.join().unwrap();
@@ -0,0 +1,118 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::{BigQueue, Error, Result};
+
+impl BigQueue {
+    /// Append every record in `records` and persist the tail once. Returns
+    /// the sequence number of the first one, see `push`. Either all records
+    /// are pushed or none.
+    pub fn push_batch(&mut self, records: &[&[u8]]) -> Result<u64> {
+        self.check_writable()?;
+        let start = self.tail_pos();
+        for record in records {
+            if let Err(e) = self.append(record) {
+                let (count, bytes) = self.index.get_tail_counts();
+                self.reset_tail(start, count, bytes)?;
+                return Err(e);
+            }
+        }
+        let pushed = self.commit_tail()?;
+        Ok(pushed - records.len() as u64)
+    }
+
+    /// Pop up to `max_records` records holding at most `max_bytes` of
+    /// payload between them, and persist the head once. The first record
+    /// is returned even if it alone is larger than `max_bytes`.
+    ///
+    /// A record that fails to read ends the batch; it is reported by the
+    /// next call if the batch already holds records.
+    pub fn pop_batch(&mut self, max_records: usize, max_bytes: usize) -> Result<Vec<Vec<u8>>> {
+        self.check_writable()?;
+        if self.is_empty() {
+            return Err(Error::QueueEmpty);
+        }
+
+        let mut batch = Vec::new();
+        let mut bytes = 0;
+        while batch.len() < max_records && !self.is_empty() {
+            let (aid, offset) = (self.head_aid, self.head_offset);
+            let popped = (self.popped, self.popped_bytes);
+            let data = match self.read_next() {
+                Ok(v) => v,
+                Err(e) if batch.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+            if !batch.is_empty() && bytes + data.len() > max_bytes {
+                self.seek_head(aid, offset)?;
+                self.popped = popped.0;
+                self.popped_bytes = popped.1;
+                break;
+            }
+            bytes += data.len();
+            batch.push(data);
+        }
+        if !batch.is_empty() {
+            self.persist_head(batch.len() as u64)?;
+        }
+        Ok(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{BigQueue, Config, Durability};
+
+    #[test]
+    fn test_push_and_pop_batch() {
+        let dir = "/tmp/bigqueue_test_batch";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        let mut q = BigQueue::with_config(dir, true, conf.clone()).unwrap();
+
+        q.push(b"first").unwrap();
+        let records: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 10 + i as usize]).collect();
+        let refs: Vec<&[u8]> = records.iter().map(|r| r.as_slice()).collect();
+        assert_eq!(q.push_batch(&refs).unwrap(), 1);
+        assert_eq!(q.push_batch(&[]).unwrap(), 11);
+        assert_eq!(q.len(), 11);
+
+        assert_eq!(q.pop_batch(100, 1).unwrap(), vec![b"first".to_vec()]);
+        assert_eq!(q.pop_batch(3, 1000).unwrap(), records[0..3].to_vec());
+        // 13 + 14 bytes fit, the next 15 do not
+        assert_eq!(q.pop_batch(100, 30).unwrap(), records[3..5].to_vec());
+        assert!(q.pop_batch(0, 1000).unwrap().is_empty());
+        drop(q);
+
+        let mut q = BigQueue::with_config(dir, false, conf).unwrap();
+        assert_eq!(q.next_seq(), 6);
+        assert_eq!(q.pop_batch(100, 1000).unwrap(), records[5..].to_vec());
+        assert!(q.pop_batch(100, 1000).is_err());
+    }
+
+    #[test]
+    fn test_batch_sync_every_n() {
+        let dir = "/tmp/bigqueue_test_batch_sync";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.durability = Durability::EveryN(10);
+        let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+
+        // every record counts toward the policy, not every batch
+        let records = vec![&b"record"[..]; 500];
+        q.push_batch(&records).unwrap();
+        assert_eq!(q.synced_pos, q.tail_pos());
+        assert_eq!(q.unsynced, 0);
+        assert_eq!(q.pop_batch(400, 1 << 20).unwrap().len(), 400);
+        assert_eq!(q.unsynced, 0);
+        q.pop().unwrap();
+        assert_eq!(q.unsynced, 1);
+    }
+}
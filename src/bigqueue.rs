@@ -5,71 +5,113 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use std::{fmt, fs};
-use std::cell::UnsafeCell;
-use std::fs::OpenOptions;
+use std::{fmt, fs, mem};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::IoSlice;
 use std::fs::ReadDir;
 use std::ops::Range;
 use std::path::Path;
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Instant;
 
 use lru::LruCache;
-use memmap::MmapMut;
+use memmap::{Mmap, MmapMut, MmapOptions};
 
-use crate::{BigQueue, read_u64, write_bytes, write_u64};
+use crate::{BigQueue, read_u64, Subscription, write_bytes, write_u64};
+use crate::{transform_array_of_u8_to_u32, transform_array_of_u8_to_u64};
+use crate::{transform_u32_to_array_of_u8, transform_u64_to_array_of_u8};
 use crate::{Error, Result};
-use crate::Config;
+use crate::{Config, Durability};
+use crate::lock::DirLock;
+use crate::delivery;
+use crate::process;
+use crate::seek::seek_index_path;
 
 impl BigQueue {
     pub fn with_config(_dir: &str, reset: bool, conf: Config) -> Result<BigQueue> {
-        match fs::metadata(_dir) {
-            Ok(v) => {
-                if !v.is_dir() {
-                    return Err(Error::IsDir(_dir.to_string()));
-                }
-                if v.permissions().readonly() {
-                    return Err(Error::IsDir(_dir.to_string()));
-                }
-            }
-            Err(_) => {
-                return Err(Error::Exist(_dir.to_string()));
-            }
-        }
+        check_dir(_dir)?;
+        let lock = DirLock::acquire(Path::new(_dir), conf.lock)?;
+        BigQueue::with_lock(_dir, reset, conf, lock)
+    }
+
+    /// Open the queue in `_dir` once `lock` is held.
+    pub(crate) fn with_lock(_dir: &str, reset: bool, conf: Config, lock: DirLock) -> Result<BigQueue> {
+        let lock = Arc::new(lock);
         if reset {
+            if lock.read_only() {
+                return Err(Error::ReadOnly);
+            }
             let read_dir = fs::read_dir(_dir)
-                .expect(&format!("fail to read directory {}", _dir));
+                .unwrap_or_else(|_| panic!("fail to read directory {}", _dir));
             delete_dir_contents(read_dir);
         }
 
-        let q_index = match Index::new(_dir) {
-            Ok(v) => { v }
-            Err(err) => {
-                return Err(err);
-            }
-        };
+        let conf = open_meta(Path::new(_dir), conf, lock.read_only())?;
+        let recover = conf.recover;
+        let index = if lock.read_only() { Index::read_only(_dir)? } else { Index::new(_dir)? };
+        let mut queue = BigQueue::with_index(_dir, index, conf, lock)?;
+        if recover {
+            queue.recover()?;
+        }
+        if !queue.lock.read_only() {
+            queue.load_pending()?;
+        }
+        Ok(queue)
+    }
+
+    pub fn new(dir: &str, reset: bool) -> Result<BigQueue> {
+        BigQueue::with_config(dir, reset, Config::new())
+    }
+
+    /// Open the queue in `dir` to look at it, even while another process
+    /// owns it. Files are mapped read-only and never created, resized or
+    /// deleted, no lock is taken, and every method that would change the
+    /// queue fails with `Error::ReadOnly`. `peek` reloads the cursors
+    /// first, see `refresh`.
+    pub fn open_read_only(dir: &str) -> Result<BigQueue> {
+        if !fs::metadata(dir).map(|v| v.is_dir()).unwrap_or(false) {
+            return Err(Error::Exist(dir.to_string()));
+        }
+        BigQueue::with_lock(dir, false, Config::new(), DirLock::none())
+    }
+
+    /// Configuration in effect. `arena_size` and `checksum` come from the
+    /// queue's metadata file when it already existed.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Open another handle on the same queue, sharing this one's lock.
+    pub(crate) fn reopen(&self) -> Result<BigQueue> {
+        BigQueue::open_handle(&self.dir, self.config.clone(), self.lock.clone())
+    }
+
+    /// Open a handle on the queue in `dir` under `lock`, which another
+    /// handle already took.
+    pub(crate) fn open_handle(dir: &Path, conf: Config, lock: Arc<DirLock>) -> Result<BigQueue> {
+        let dir = dir.to_string_lossy();
+        let index = if lock.read_only() { Index::read_only(&dir)? } else { Index::new(&dir)? };
+        BigQueue::with_index(&dir, index, conf, lock)
+    }
+
+    /// Open a handle whose head is read from and written to `q_index`.
+    pub(crate) fn with_index(_dir: &str, q_index: Index, conf: Config, lock: Arc<DirLock>) -> Result<BigQueue> {
         let (h_aid, h_offset) = q_index.get_head_tuple().expect("read index error");
         let (t_aid, t_offset) = q_index.get_tail_tuple().expect("read index error");
-        let num_arenas = t_aid + 1 - h_aid;
-
+        let (popped, popped_bytes) = q_index.get_head_counts();
 
         let q_config = conf;
         let q_dir = PathBuf::from(_dir);
 
-        let mut tail: Option<Rc<UnsafeCell<Arena>>> = None;
-        let mut head: Option<Rc<UnsafeCell<Arena>>> = None;
-
-        let t_arena = BigQueue::open_a_arena(_dir, &q_config, t_aid).expect(&format!("error to memmap data file {}", t_aid));
-        let arc = Rc::new(UnsafeCell::new(t_arena));
-        tail = Some(arc.clone());
-
-        if h_aid != t_aid {
-            let h_arena = BigQueue::open_a_arena(_dir, &q_config, h_aid).expect(&format!("error to memmap data file {}", h_aid));
-            head = Some(Rc::new(UnsafeCell::new(h_arena)));
-        } else {
-            head = Some(arc);
-        }
+        // head and tail own separate mappings, even when they share an arena file
+        let tail = BigQueue::open_a_arena(_dir, &q_config, t_aid, lock.read_only())?;
+        let head = BigQueue::open_a_arena(_dir, &q_config, h_aid, lock.read_only())?;
 
+        let synced_pos = (t_aid * q_config.arena_size + t_offset) as u64;
         let queue = BigQueue {
             index: q_index,
             config: q_config,
@@ -78,14 +120,48 @@ impl BigQueue {
             head_offset: h_offset,
             tail_aid: t_aid,
             tail_offset: t_offset,
-            q_head: head.expect("open file error"),
-            q_tail: tail.expect("open file error"),
+            popped,
+            popped_bytes,
+            appended: 0,
+            appended_bytes: 0,
+            marks: Vec::new(),
+            q_head: head,
+            q_tail: tail,
             cache: LruCache::new(3),
+            deliveries: BTreeMap::new(),
+            next_delivery: 0,
+            synced_pos,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            recovery: None,
+            lock,
         };
         Ok(queue)
     }
-    pub fn new(dir: &str, reset: bool) -> Result<BigQueue> {
-        BigQueue::with_config(dir, reset, Config::new())
+
+    /// Open the named subscription, creating it at the current head if it
+    /// does not exist yet. Every subscription reads every record through
+    /// its own persisted cursor.
+    ///
+    /// Once the queue has subscriptions, `shrink` follows them alone:
+    /// records all of them have read are deleted, and the queue's own head
+    /// is moved past them.
+    pub fn subscribe(&self, name: &str) -> Result<Subscription> {
+        self.check_writable()?;
+        let lock = subscription_lock(&self.dir, name)?;
+        let dir = self.dir.to_string_lossy();
+        let index = Index::subscription(&dir, name)?;
+        let queue = BigQueue::with_index(&dir, index, self.config.clone(), self.lock.clone())?;
+        Ok(Subscription::new(name, queue, lock))
+    }
+
+    /// Delete the named subscription so it no longer holds back `shrink`.
+    /// Fails with `Error::Locked` while the subscription is open.
+    pub fn unsubscribe(&self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let path = subscription_path(&self.dir, name)?;
+        let _lock = subscription_lock(&self.dir, name)?;
+        fs::remove_file(&path).map_err(Error::Io)
     }
 
     pub fn is_empty(&self) -> bool {
@@ -93,201 +169,568 @@ impl BigQueue {
     }
 
     pub fn peek(&mut self) -> Result<Vec<u8>> {
+        if self.lock.read_only() {
+            self.refresh()?;
+        }
         if self.is_empty() {
             return Err(Error::QueueEmpty);
         }
 
         let old_aid: usize = self.head_aid;
         let old_offset: usize = self.head_offset;
-        let head = self.q_head.clone();
-
-        let read_length = self.read_length();
-        if read_length.is_none() {
-            return Err(Error::ReadLength);
-        }
-        let length = read_length.unwrap();
-        let mut result: Vec<u8> = Vec::with_capacity(length);
 
-        if let Some(data) = self.read_bytes(length) {
-            result.extend(data);
-        } else {
-            return Err(Error::Read);
-        }
+        let result = self.read_record();
 
-        self.head_offset = old_offset;
-        self.change_head(old_aid, head);
-        return Ok(result);
+        self.seek_head(old_aid, old_offset)?;
+        result
     }
 
     pub fn pop(&mut self) -> Result<Vec<u8>> {
+        self.check_writable()?;
+        let result = self.read_next()?;
+        self.set_head_index(self.head_aid, self.head_offset)?;
+        Ok(result)
+    }
+
+    /// Pop the head record, handing its payload to `f` before the head
+    /// moves. The slice borrows the arena mapping directly unless the
+    /// record straddles two arenas, in which case it is copied once.
+    pub fn pop_with<F, R>(&mut self, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> R {
+        self.check_writable()?;
         if self.is_empty() {
             return Err(Error::QueueEmpty);
         }
 
-        let read_length = self.read_length();
-        if read_length.is_none() {
-            return Err(Error::ReadLength);
-        }
-        let length = read_length.unwrap();
-        let mut result: Vec<u8> = Vec::with_capacity(length);
+        let old_aid: usize = self.head_aid;
+        let old_offset: usize = self.head_offset;
 
-        if let Some(data) = self.read_bytes(length) {
-            result.extend(data);
-            self.set_head_index(self.head_aid, self.head_offset);
-        } else {
-            return Err(Error::Read);
+        let mut length = 0;
+        match self.read_record_with(|data| { length = data.len(); f(data) }) {
+            Ok(result) => {
+                self.count_popped(length);
+                self.set_head_index(self.head_aid, self.head_offset)?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.seek_head(old_aid, old_offset)?;
+                Err(e)
+            }
         }
+    }
 
-        return Ok(result);
+    /// Append a record and return its sequence number. Records are
+    /// numbered from 0 in push order, the numbers survive a reopen.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<u64> {
+        self.check_writable()?;
+        self.append(bytes)?;
+        self.commit_tail().map(|pushed| pushed - 1)
     }
 
-    pub fn push(&mut self, bytes: &[u8]) -> Result<()> {
-        let length = bytes.len();
-        let n_offset = self.write_length(self.tail_offset, length as u64);
-        self.write_bytes(n_offset, bytes).expect("fail to write when push");
-        self.set_tail_index(self.tail_aid, self.tail_offset);
-        Ok(())
+    /// Append one record made of `parts` written back to back, as if they
+    /// were concatenated first. Returns its sequence number, see `push`.
+    pub fn push_vectored(&mut self, parts: &[IoSlice]) -> Result<u64> {
+        self.check_writable()?;
+        self.append_vectored(parts)?;
+        self.commit_tail().map(|pushed| pushed - 1)
+    }
+
+    /// Sequence number of the record the next `pop` returns, see `push`.
+    pub fn next_seq(&self) -> u64 {
+        self.popped
     }
 
     pub fn dequeue(&mut self) -> Result<()> {
+        self.check_writable()?;
         if self.is_empty() {
             return Err(Error::QueueEmpty);
         }
-        if let Some(length) = self.read_length() {
-            let mut head_aid = self.head_aid;
-            let mut head_offset = self.head_offset;
-
-            if (head_offset + length) >= self.config.arena_size {
-                head_aid = head_aid + 1;
-                self.flip_head_page_to(head_aid).expect("fail to flip next page");
-                head_offset = (head_offset + length - self.config.arena_size) % self.config.arena_size;
-            } else {
-                head_offset = (head_offset + length) % self.config.arena_size;
-            }
-            self.set_head_index(head_aid, head_offset);
-        } else {
-            return Err(Error::ReadLength);
-        }
-        Ok(())
+        let (start, length) = self.record_start(self.head_pos(), self.tail_pos())?;
+        self.advance_head(start + length + self.trailer_len() as u64, length as usize)
     }
 
+    /// Delete the arenas every consumer has moved past: unacknowledged
+    /// deliveries and all subscriptions, or the queue head while nothing
+    /// subscribes. Once the queue has subscriptions, its own head is moved
+    /// up to the oldest of them when it lags behind.
     pub fn shrink(&mut self) {
-        let read_dir = fs::read_dir(&self.dir);
-        if read_dir.is_err() {
+        if self.check_writable().is_err() {
             return;
         }
-        let read_dir_res = read_dir.unwrap();
-        for entry in read_dir_res {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                let ext = path.clone().into_os_string().into_string().unwrap();
-                if ext.ends_with(".dat") {
-                    let part: Vec<&str> = ext.split("_").collect();
-                    if part.len() != 2 {
-                        continue;
-                    }
-                    let part: Vec<&str> = part[1].split(".").collect();
-                    let index_usize = part[0].parse::<usize>().unwrap();
-                    if (
-                        self.tail_aid >= self.head_aid && index_usize < self.head_aid)
-                        ||
-                        (
-                            self.tail_aid < self.head_aid
-                                && (index_usize > self.tail_aid && index_usize < self.head_aid
-                            )
-                        ) {
-                        if let Ok(_) = fs::remove_file(path) {}
-                    }
-                }
+        let read_dir = match fs::read_dir(&self.dir) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        let (oldest, count, bytes) = match self.oldest_head() {
+            Some(v) => v,
+            None => return,
+        };
+        let (head_aid, head_offset) = self.split_pos(oldest);
+        if oldest > self.head_pos() {
+            if self.seek_head(head_aid, head_offset).is_err() {
+                return;
+            }
+            self.popped = count;
+            self.popped_bytes = bytes;
+            if self.persist_head(0).is_err() {
+                return;
+            }
+        }
+        for entry in read_dir.flatten() {
+            let path = entry.path();
+            let index_usize = match arena_id(&entry.file_name().to_string_lossy()) {
+                Some(v) => v,
+                None => continue,
             };
+            if index_usize < head_aid
+                && (self.tail_aid >= head_aid || index_usize > self.tail_aid) {
+                let _ = fs::remove_file(path);
+                let _ = fs::remove_file(seek_index_path(&self.dir, index_usize));
+            }
         }
     }
 }
 
 impl Drop for BigQueue {
     fn drop(&mut self) {
+        if self.check_writable().is_err() {
+            return;
+        }
+        if self.config.durability != Durability::None {
+            let _ = self.sync();
+        }
         self.cache.clear();
         self.shrink();
     }
 }
 
 impl BigQueue {
-    fn open_a_arena(_dir: &str, config: &Config, aid: usize) -> Result<Arena> {
+    fn open_a_arena(_dir: &str, config: &Config, aid: usize, read_only: bool) -> Result<Arena> {
         let dir = PathBuf::from(_dir);
         let data_path = dir.join(format!("arena_{}.dat", aid));
-        return Arena::new(data_path, config.arena_size);
+        Arena::open(data_path, config.arena_size, read_only)
+    }
+
+    /// Fail with `Error::ReadOnly` on a handle opened with a shared lock.
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.lock.read_only() {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Move the head past a record of `length` bytes ending at `pos` and
+    /// persist it.
+    pub(crate) fn advance_head(&mut self, pos: u64, length: usize) -> Result<()> {
+        let (aid, offset) = self.split_pos(pos);
+        self.seek_head(aid, offset)?;
+        self.count_popped(length);
+        self.set_head_index(aid, offset)
+    }
+
+    /// Account for a record of `length` bytes the in-memory head moved past.
+    fn count_popped(&mut self, length: usize) {
+        self.popped += 1;
+        self.popped_bytes += length as u64;
     }
 
-    fn set_head_index(&mut self, aid: usize, offset: usize) {
-        self.index.set_head(aid, offset);
+    /// Persist a head moved past one record.
+    fn set_head_index(&mut self, aid: usize, offset: usize) -> Result<()> {
         self.head_aid = aid;
         self.head_offset = offset;
+        self.persist_head(1)
+    }
+
+    /// Persist the head after `records` records were popped.
+    pub(crate) fn persist_head(&mut self, records: u64) -> Result<()> {
+        self.index.set_head(self.head_aid, self.head_offset, self.popped, self.popped_bytes)?;
+        self.maybe_sync(records)
+    }
+
+    /// Read the head record and move the in-memory head past it, leaving
+    /// the head untouched on error.
+    pub(crate) fn read_next(&mut self) -> Result<Vec<u8>> {
+        if self.is_empty() {
+            return Err(Error::QueueEmpty);
+        }
+
+        let old_aid: usize = self.head_aid;
+        let old_offset: usize = self.head_offset;
+
+        let result = self.read_record();
+        match &result {
+            Ok(data) => self.count_popped(data.len()),
+            Err(_) => self.seek_head(old_aid, old_offset)?,
+        }
+        result
+    }
+
+    /// Read the record starting at `pos` without moving the head. Returns
+    /// the payload and the position right after the record.
+    pub(crate) fn read_at(&mut self, pos: u64) -> Result<(Vec<u8>, u64)> {
+        let old_aid: usize = self.head_aid;
+        let old_offset: usize = self.head_offset;
+
+        let (aid, offset) = self.split_pos(pos);
+        let result = match self.seek_head(aid, offset) {
+            Ok(()) => self.read_record().map(|data| (data, self.head_pos())),
+            Err(e) => Err(e),
+        };
+        self.seek_head(old_aid, old_offset)?;
+        result
+    }
+
+    /// Validate the record starting at `pos` without moving the head and
+    /// return the position right after it and its payload length. The
+    /// payload is only read when there is a checksum to verify.
+    pub(crate) fn check_at(&mut self, pos: u64) -> Result<(u64, usize)> {
+        self.check_within(pos, self.tail_pos())
+    }
+
+    /// Like `check_at`, for a record that must end by `end`.
+    pub(crate) fn check_within(&mut self, pos: u64, end: u64) -> Result<(u64, usize)> {
+        let (start, length) = self.record_start(pos, end)?;
+        if self.config.checksum {
+            let mut data = vec![0u8; length as usize];
+            self.copy_at(start, &mut data)?;
+            self.check_crc_at(start, length, record_crc(&data))?;
+        }
+        Ok((start + length + self.trailer_len() as u64, length as usize))
+    }
+
+    /// Fill `buf` with the bytes starting at `pos`, crossing arenas as
+    /// needed. Leaves the head alone: arenas other than the head's come
+    /// from the cache, loaded into it on a miss.
+    pub(crate) fn copy_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        let mut pos = pos;
+        let mut done = 0;
+        while done < buf.len() {
+            let (aid, offset) = self.split_pos(pos);
+            let n = (buf.len() - done).min(self.config.arena_size - offset);
+            self.cache_arena(aid)?;
+            let src = self.arena_bytes(aid).and_then(|b| b.get(offset..offset + n)).ok_or(Error::Read)?;
+            buf[done..done + n].copy_from_slice(src);
+            done += n;
+            pos += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Make sure arena `aid` is mapped, as the head arena or in the cache.
+    pub(crate) fn cache_arena(&mut self, aid: usize) -> Result<()> {
+        if aid != self.head_aid && !self.cache.contains(&aid) {
+            let arena = self.load_arena(aid)?;
+            self.cache.put(aid, arena);
+        }
+        Ok(())
+    }
+
+    /// Contents of arena `aid` if it is mapped, see `cache_arena`.
+    pub(crate) fn arena_bytes(&self, aid: usize) -> Option<&[u8]> {
+        if aid == self.head_aid {
+            return Some(self.q_head.bytes());
+        }
+        self.cache.peek(&aid).map(|arena| arena.bytes())
+    }
+
+    pub(crate) fn split_pos(&self, pos: u64) -> (usize, usize) {
+        (pos as usize / self.config.arena_size, pos as usize % self.config.arena_size)
+    }
+
+    /// Move the in-memory head, mapping its arena.
+    pub(crate) fn seek_head(&mut self, aid: usize, offset: usize) -> Result<()> {
+        if self.head_aid != aid {
+            self.flip_head_page_to(aid)?;
+        }
+        self.head_offset = offset;
+        Ok(())
     }
 
-    fn set_tail_index(&mut self, aid: usize, offset: usize) {
-        self.index.set_tail(aid, offset);
+    fn set_tail_index(&mut self, aid: usize, offset: usize, count: u64, bytes: u64) {
+        self.index.set_tail(aid, offset, count, bytes).expect("fail to write index");
         self.tail_aid = aid;
         self.tail_offset = offset;
+        self.index.publish_tail(self.tail_pos());
+    }
+
+    /// Move the in-memory head to `pos`, see `seek_head`.
+    fn seek_head_pos(&mut self, pos: u64) -> Result<()> {
+        let (aid, offset) = self.split_pos(pos);
+        self.seek_head(aid, offset)
+    }
+
+    /// Position of the head as a byte offset into the whole queue.
+    pub(crate) fn head_pos(&self) -> u64 {
+        (self.head_aid * self.config.arena_size + self.head_offset) as u64
+    }
+
+    /// Position of the tail as a byte offset into the whole queue.
+    pub(crate) fn tail_pos(&self) -> u64 {
+        (self.tail_aid * self.config.arena_size + self.tail_offset) as u64
+    }
+
+    /// Oldest position still needed, over every subscription cursor, or
+    /// the queue head when there are none, and the oldest unacknowledged
+    /// delivery. Returned with the records and bytes consumed up to it.
+    pub(crate) fn oldest_head(&self) -> Option<(u64, u64, u64)> {
+        let pos = |c: &Cursor| (c.aid * self.config.arena_size + c.offset) as u64;
+        let mut subscriptions = Vec::new();
+        for entry in fs::read_dir(&self.dir).ok()?.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SUBSCRIPTION_EXT) {
+                continue;
+            }
+            // a cursor we cannot read must keep every arena alive
+            subscriptions.push(read_cursor(&Arena::new(path, SUBSCRIPTION_FILE_SIZE).ok()?, HEAD_CURSOR));
+        }
+        let mut oldest = subscriptions.into_iter()
+            .min_by_key(|c| pos(c))
+            .unwrap_or_else(|| read_cursor(&self.index.arena, HEAD_CURSOR));
+        if let Some(&(delivery, count, bytes)) = delivery::read_pending(&self.dir).ok()?.first() {
+            if delivery < pos(&oldest) {
+                let (aid, offset) = self.split_pos(delivery);
+                oldest = Cursor { aid, offset, count, bytes, ..oldest };
+            }
+        }
+        Some((pos(&oldest), oldest.count, oldest.bytes))
     }
 
-    fn get_head_map(&mut self) -> &mut memmap::MmapMut {
-        let m = unsafe { (&mut (*self.q_head.get()).mmap) };
-        return m;
+    /// Reload the head and tail persisted by the handle that owns the queue,
+    /// so a read-only handle follows a queue that is in use. A no-op on the
+    /// handle that owns the queue.
+    pub fn refresh(&mut self) -> Result<()> {
+        if !self.lock.read_only() {
+            return Ok(());
+        }
+        let (aid, offset) = self.index.get_head_tuple().ok_or(Error::Read)?;
+        self.seek_head(aid, offset)?;
+        let (popped, popped_bytes) = self.index.get_head_counts();
+        self.popped = popped;
+        self.popped_bytes = popped_bytes;
+        self.refresh_tail();
+        Ok(())
+    }
+
+    /// Reload the tail published by the handle that owns it, which may
+    /// live in another process, see `sync_tail`.
+    pub(crate) fn refresh_tail(&mut self) {
+        let pos = match self.index.published_tail() {
+            Some(pos) => pos,
+            None => match self.index.get_tail_tuple() {
+                Some((aid, offset)) => (aid * self.config.arena_size + offset) as u64,
+                None => return,
+            },
+        };
+        self.sync_tail(pos);
+    }
+
+    /// Write a record at the in-memory tail without persisting the tail.
+    ///
+    /// A record is its length as a little-endian u64 followed by the
+    /// payload and, with `Config::checksum`, a CRC32C of both.
+    pub(crate) fn append(&mut self, bytes: &[u8]) -> Result<()> {
+        self.append_vectored(&[IoSlice::new(bytes)])
+    }
+
+    /// Like `append`, with the payload made of `parts` back to back.
+    pub(crate) fn append_vectored(&mut self, parts: &[IoSlice]) -> Result<()> {
+        let length: usize = parts.iter().map(|part| part.len()).sum();
+        self.mark(self.tail_pos());
+        let mut crc = crc32c::crc32c(&transform_u64_to_array_of_u8(length as u64));
+        let n_offset = self.write_length(self.tail_offset, length as u64);
+        self.tail_offset = n_offset;
+        for part in parts {
+            self.write_bytes(self.tail_offset, part)?;
+            crc = crc32c::crc32c_append(crc, part);
+        }
+        self.end_record(length, crc)
+    }
+
+    /// Write the checksum `crc` after a payload of `length` bytes ending
+    /// at the in-memory tail and count the record as appended.
+    pub(crate) fn end_record(&mut self, length: usize, crc: u32) -> Result<()> {
+        if self.config.checksum {
+            self.write_bytes(self.tail_offset, &transform_u32_to_array_of_u8(crc))?;
+        }
+        self.appended += 1;
+        self.appended_bytes += length as u64;
+        Ok(())
+    }
+
+    /// Persist the in-memory tail, publishing the appended records, and
+    /// return the number of records pushed so far. The appended records are
+    /// counted on top of the previous commit, which may come from another
+    /// handle.
+    pub(crate) fn commit_tail(&mut self) -> Result<u64> {
+        let (count, bytes) = self.index.get_tail_counts();
+        let records = self.appended;
+        let pushed = count + records;
+        let marks = mem::take(&mut self.marks);
+        self.persist_tail(self.tail_pos(), pushed, bytes + self.appended_bytes)?;
+        self.write_marks(marks, count, bytes);
+        self.maybe_sync(records)?;
+        Ok(pushed)
+    }
+
+    /// Move the tail to `pos`, the end of the `count`th record pushed, and
+    /// persist it, dropping anything appended and not committed.
+    pub(crate) fn reset_tail(&mut self, pos: u64, count: u64, bytes: u64) -> Result<()> {
+        self.persist_tail(pos, count, bytes)?;
+        self.maybe_sync(0)
+    }
+
+    fn persist_tail(&mut self, pos: u64, count: u64, bytes: u64) -> Result<()> {
+        self.seek_tail(pos)?;
+        self.appended = 0;
+        self.appended_bytes = 0;
+        self.marks.clear();
+        self.set_tail_index(self.tail_aid, self.tail_offset, count, bytes);
+        Ok(())
+    }
+
+    /// Flush every arena range written since the last sync, then the index,
+    /// so a persisted tail never points at data that is not on disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let (synced_aid, synced_offset) = self.split_pos(self.synced_pos);
+        for aid in synced_aid..=self.tail_aid {
+            let start = if aid == synced_aid { synced_offset } else { 0 };
+            let end = if aid == self.tail_aid { self.tail_offset } else { self.config.arena_size };
+            if end <= start {
+                continue;
+            }
+            if aid == self.tail_aid {
+                self.q_tail.flush_range(start, end - start)?;
+            } else if let Some(arena) = self.cache.peek(&aid) {
+                arena.flush_range(start, end - start)?;
+            } else if let Ok(arena) = self.load_arena(aid) {
+                // the file of a consumed arena may already be gone
+                arena.flush_range(start, end - start)?;
+            }
+        }
+        self.index.flush()?;
+
+        self.synced_pos = self.tail_pos();
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Sync if the durability policy asks for it now that `records` more
+    /// records were pushed or popped.
+    fn maybe_sync(&mut self, records: u64) -> Result<()> {
+        self.unsynced += records;
+        let due = match self.config.durability {
+            Durability::None => false,
+            Durability::Always => true,
+            Durability::EveryN(n) => self.unsynced >= n,
+            Durability::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if due {
+            self.sync()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Position right after a record of `length` bytes written at `pos`,
+    /// following the same layout rules as `write_length`/`write_bytes`.
+    pub(crate) fn record_end(&self, pos: u64, length: usize) -> u64 {
+        header_pos(pos, self.config.arena_size as u64) + 8 + (length + self.trailer_len()) as u64
+    }
+
+    /// Read the length of the record starting at `pos`, rejecting one whose
+    /// payload and checksum would run past `end`. Returns the position of
+    /// the payload and its length. Leaves the head alone, see `copy_at`.
+    pub(crate) fn record_start(&mut self, pos: u64, end: u64) -> Result<(u64, u64)> {
+        let pos = header_pos(pos, self.config.arena_size as u64);
+        let mut length = [0u8; 8];
+        self.copy_at(pos, &mut length)?;
+        let length = transform_array_of_u8_to_u64(&length);
+        let start = pos + 8;
+        if length > end.saturating_sub(start).saturating_sub(self.trailer_len() as u64) {
+            let (aid, offset) = self.split_pos(pos);
+            return Err(Error::Corrupted { aid, offset });
+        }
+        Ok((start, length))
+    }
+
+    /// Compare `crc`, see `record_crc`, with the checksum stored after the
+    /// payload of `length` bytes at `start`. Passes without checksums.
+    pub(crate) fn check_crc_at(&mut self, start: u64, length: u64, crc: u32) -> Result<()> {
+        if !self.config.checksum {
+            return Ok(());
+        }
+        let mut stored = [0u8; 4];
+        self.copy_at(start + length, &mut stored)?;
+        if transform_array_of_u8_to_u32(&stored) != crc {
+            let (aid, offset) = self.split_pos(start - 8);
+            return Err(Error::Corrupted { aid, offset });
+        }
+        Ok(())
+    }
+
+    /// Move the tail, mapping its arena, to a position reserved by the caller.
+    pub(crate) fn seek_tail(&mut self, pos: u64) -> Result<()> {
+        let aid = pos as usize / self.config.arena_size;
+        if aid != self.tail_aid {
+            let arena = match self.cache.pop(&aid) {
+                Some(a) => a,
+                None => self.open_arena(aid)?,
+            };
+            self.set_tail(aid, arena);
+        }
+        self.tail_offset = pos as usize % self.config.arena_size;
+        Ok(())
+    }
+
+    /// Adopt a tail published by another handle on the same directory.
+    /// That handle flushes the records up to it, so `sync` here only
+    /// flushes the cursors and `q_tail` need not follow.
+    pub(crate) fn sync_tail(&mut self, pos: u64) {
+        self.tail_aid = pos as usize / self.config.arena_size;
+        self.tail_offset = pos as usize % self.config.arena_size;
+        self.synced_pos = pos;
     }
 
     fn get_tail(&mut self) -> &mut Arena {
-        let m = unsafe { (&mut (*self.q_tail.get())) };
-        return m;
+        &mut self.q_tail
     }
 
     #[inline]
     fn set_tail(&mut self, id: usize, a: Arena) {
-        self.q_tail = Rc::new(UnsafeCell::new(a));
+        let old = mem::replace(&mut self.q_tail, a);
+        self.cache.put(self.tail_aid, old);
         self.tail_aid = id;
     }
 
     #[inline]
     fn set_head(&mut self, id: usize, a: Arena) {
-        self.q_head = Rc::new(UnsafeCell::new(a));
+        let old = mem::replace(&mut self.q_head, a);
+        self.cache.put(self.head_aid, old);
         self.head_aid = id;
     }
 
-    #[inline]
-    fn change_head(&mut self, id: usize, a: Rc<UnsafeCell<Arena>>) {
-        self.head_aid = id;
-        self.q_head = a;
-    }
-
     #[inline]
     fn flip_head_page_to(&mut self, aid: usize) -> Result<()> {
         if let Some(a) = self.cache.pop(&aid) {
-            if let Ok(aa) = Rc::try_unwrap(a) {
-                self.set_head(aid, aa.into_inner());
-                return Ok(());
-            }
+            self.set_head(aid, a);
+            return Ok(());
         }
         self.set_head(aid, self.load_arena(aid)?);
         Ok(())
     }
 
     #[inline]
-    fn flip_tail_page_forward(&mut self) {
-        let old_aid = self.tail_aid;
-        {
-            let z = self.q_tail.clone();
-            self.cache.put(old_aid, z);
-        }
-
-        let aid = 1 + old_aid;
-        self.set_tail(aid, Arena {
-            mmap: self.open_arena(aid).expect("load arena error").mmap,
-        });
+    pub(crate) fn flip_tail_page_forward(&mut self) {
+        let aid = 1 + self.tail_aid;
+        self.set_tail(aid, self.open_arena(aid).expect("load arena error"));
     }
 
     #[inline]
     fn open_arena(&self, aid: usize) -> Result<Arena> {
         let data_path = self.dir.join(format!("arena_{}.dat", aid));
-        return Arena::new(data_path, self.config.arena_size);
+        Arena::open(data_path, self.config.arena_size, self.lock.read_only())
     }
 
     #[inline]
@@ -296,26 +739,18 @@ impl BigQueue {
         if !data_path.exists() {
             return Err(Error::Exist(data_path.to_string_lossy().to_string()));
         }
-        return Arena::new(data_path, self.config.arena_size);
-    }
-
-    fn delete_arena_backed_file(&self, aid: usize) -> bool {
-        let file_path = self.dir.join(format!("arena_{}.dat", aid));
-        if let Err(_) = fs::remove_file(file_path) {
-            return false;
-        }
-        true
+        Arena::open(data_path, self.config.arena_size, self.lock.read_only())
     }
 
     #[inline]
-    fn write_length(&mut self, offset: usize, length: u64) -> usize {
+    pub(crate) fn write_length(&mut self, offset: usize, length: u64) -> usize {
         let mut i_offset = offset;
         if i_offset + 8 > self.config.arena_size {
             self.flip_tail_page_forward();
             i_offset = 0;
         }
-        self.get_tail().write_u64_at(i_offset, length);
-        i_offset = i_offset + 8;
+        self.get_tail().write_u64_at(i_offset, length).expect("fail to write length");
+        i_offset += 8;
         if i_offset == self.config.arena_size {
             self.flip_tail_page_forward();
             i_offset = 0;
@@ -324,7 +759,7 @@ impl BigQueue {
     }
 
     #[inline]
-    fn write_bytes(&mut self, offset: usize, bytes: &[u8]) -> Result<()> {
+    pub(crate) fn write_bytes(&mut self, offset: usize, bytes: &[u8]) -> Result<()> {
         let length = bytes.len();
         let mut i_offset = offset;
         let mut i_length = bytes.len();
@@ -334,7 +769,7 @@ impl BigQueue {
                 let range = Range { start: count, end: count + self.config.arena_size - i_offset };
                 let write_in = bytes.get(range).unwrap();
                 self.get_tail().write_bytes_at(i_offset, write_in)?;
-                count = count + write_in.len();
+                count += write_in.len();
                 i_length = length - count;
                 self.flip_tail_page_forward();
                 i_offset = 0;
@@ -353,83 +788,270 @@ impl BigQueue {
         }
     }
 
-    #[inline]
-    fn read_length(&mut self) -> Option<(usize)> {
-        let mut offset = self.head_offset;
-        let mut next_offset = offset + 8;
-        if next_offset > self.config.arena_size {
-            self.flip_head_page_to(self.head_aid + 1);
-            offset = 0;
-            next_offset = 8;
-        }
-        if let Some(length) = read_u64(self.get_head_map(), offset) {
-            if next_offset == self.config.arena_size {
-                self.flip_head_page_to(self.head_aid + 1);
-                self.head_offset = 0;
-            } else {
-                self.head_offset = next_offset;
-            }
-            Some(length as usize)
+    /// Read the head record and move the in-memory head past it.
+    fn read_record(&mut self) -> Result<Vec<u8>> {
+        let (start, length) = self.record_start(self.head_pos(), self.tail_pos())?;
+        let mut data = vec![0u8; length as usize];
+        self.copy_at(start, &mut data)?;
+        self.check_crc_at(start, length, record_crc(&data))?;
+        self.seek_head_pos(start + length + self.trailer_len() as u64)?;
+        Ok(data)
+    }
+
+    /// Like `read_record`, but hands the payload to `f` in place when it
+    /// and its checksum sit inside one arena.
+    fn read_record_with<F, R>(&mut self, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> R {
+        let (start, length) = self.record_start(self.head_pos(), self.tail_pos())?;
+        let (aid, offset) = self.split_pos(start);
+        let end = offset + length as usize;
+        let result = if end + self.trailer_len() > self.config.arena_size {
+            let mut data = vec![0u8; length as usize];
+            self.copy_at(start, &mut data)?;
+            self.check_crc_at(start, length, record_crc(&data))?;
+            f(&data)
         } else {
-            None
-        }
+            self.cache_arena(aid)?;
+            if self.config.checksum {
+                let crc = record_crc(self.arena_bytes(aid).and_then(|b| b.get(offset..end)).ok_or(Error::Read)?);
+                self.check_crc_at(start, length, crc)?;
+            }
+            f(self.arena_bytes(aid).and_then(|b| b.get(offset..end)).ok_or(Error::Read)?)
+        };
+        self.seek_head_pos(start + length + self.trailer_len() as u64)?;
+        Ok(result)
     }
 
+    /// Bytes stored after the payload of every record.
     #[inline]
-    fn read_bytes(&mut self, length: usize) -> Option<Vec<u8>> {
-        let mut result: Vec<u8> = Vec::with_capacity(length);
-        let mut i_offset = self.head_offset;
-        let mut i_length = length;
+    pub(crate) fn trailer_len(&self) -> usize {
+        if self.config.checksum { 4 } else { 0 }
+    }
+}
 
-        loop {
-            if i_offset + i_length > self.config.arena_size {
-                let range = Range { start: i_offset, end: self.config.arena_size };
-                if let Some(slice) = self.get_head_map().get(range) {
-                    result.extend_from_slice(slice);
-                    i_length = i_length - slice.len();
-                    i_offset = 0;
-                    self.flip_head_page_to(self.head_aid + 1).expect("fail to flip page when read");
-                } else {
-                    return None;
-                }
-            } else {
-                let range = Range { start: i_offset, end: i_length + i_offset };
-                if let Some(slice) = self.get_head_map().get(range) {
-                    result.extend_from_slice(slice);
-                    if i_offset + i_length == self.config.arena_size {
-                        self.flip_head_page_to(self.head_aid + 1).expect("fail to flip page when read");
-                        self.head_offset = 0;
-                    } else {
-                        self.head_offset = i_offset + i_length;
-                    }
-                    return Some(result);
-                } else {
-                    return None;
-                }
+pub(crate) fn check_dir(dir: &str) -> Result<()> {
+    match fs::metadata(dir) {
+        Ok(v) => {
+            if !v.is_dir() {
+                return Err(Error::IsDir(dir.to_string()));
+            }
+            if v.permissions().readonly() {
+                return Err(Error::IsDir(dir.to_string()));
             }
         }
+        Err(_) => {
+            return Err(Error::Exist(dir.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Arena id of a file named `arena_<id>.dat`.
+pub(crate) fn arena_id(name: &str) -> Option<usize> {
+    name.strip_prefix("arena_")?.strip_suffix(".dat")?.parse().ok()
+}
+
+/// Position of the length of a record written at `pos`: a length that does
+/// not fit in the rest of an arena starts the next one.
+#[inline]
+pub(crate) fn header_pos(pos: u64, arena_size: u64) -> u64 {
+    if pos % arena_size + 8 > arena_size {
+        (pos / arena_size + 1) * arena_size
+    } else {
+        pos
     }
 }
 
+/// CRC32C over a record's length and payload.
+#[inline]
+pub(crate) fn record_crc(data: &[u8]) -> u32 {
+    let crc = crc32c::crc32c(&transform_u64_to_array_of_u8(data.len() as u64));
+    crc32c::crc32c_append(crc, data)
+}
+
 fn delete_dir_contents(read_dir_res: ReadDir) {
-    for entry in read_dir_res {
-        if let Ok(entry) = entry {
-            let path = entry.path();
-            let ext = path.clone().into_os_string().into_string().unwrap();
-            if ext.ends_with(".dat") {
-                fs::remove_file(path).expect("Failed to remove a file");
-            }
-        };
+    for entry in read_dir_res.flatten() {
+        let path = entry.path();
+        let ext = path.clone().into_os_string().into_string().unwrap();
+        if ext.ends_with(".dat") || ext.ends_with(".sub") || ext.ends_with(".idx") {
+            fs::remove_file(path).expect("Failed to remove a file");
+        }
     }
 }
 
 
 const INDEX_FILE: &str = "index.dat";
-const INDEX_FILE_SIZE: usize = 4 * 8;
+const SUBSCRIPTION_EXT: &str = "sub";
+
+// Every cursor is kept in two slots. An update goes to the slot that does
+// not hold the latest cursor, with a higher generation and a CRC32C over
+// the slot, so a torn write leaves the previous cursor readable.
+//
+// Besides the position, a cursor carries the number of records and payload
+// bytes pushed up to it (tail) or consumed up to it (heads).
+//
+// slot: generation u64 | aid u64 | offset u64 | count u64 | bytes u64 | reserved | crc32c u32
+const CURSOR_SLOT_SIZE: usize = 64;
+const CURSOR_CRC_OFFSET: usize = CURSOR_SLOT_SIZE - 8;
+const HEAD_CURSOR: usize = 0;
+const TAIL_CURSOR: usize = 2 * CURSOR_SLOT_SIZE;
+
+// Behind the cursors, words updated atomically by handles in any process
+// that maps the index: the tail position plus one (zero until the first
+// publication), a counter bumped on every publication and the number of
+// consumers waiting on that counter.
+const PUBLISHED_TAIL: usize = 4 * CURSOR_SLOT_SIZE;
+const NOTIFY_SEQ: usize = PUBLISHED_TAIL + 8;
+const NOTIFY_WAITERS: usize = NOTIFY_SEQ + 4;
+const INDEX_FILE_SIZE: usize = 5 * CURSOR_SLOT_SIZE;
+const SUBSCRIPTION_FILE_SIZE: usize = 2 * CURSOR_SLOT_SIZE;
+
+const META_FILE: &str = "meta.dat";
+const META_MAGIC: &[u8; 8] = b"BIGQUEUE";
+const META_FLAG_CHECKSUM: u32 = 1;
+
+// meta.dat: magic [u8; 8] | version u32 | flags u32 | arena_size u64 | crc32c u32
+const META_CRC_OFFSET: usize = 24;
+const META_FILE_SIZE: usize = META_CRC_OFFSET + 4;
+
+/// Version of the on-disk layout written by this build. Version 0 is the
+/// layout from before `meta.dat` existed.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Read the metadata of the queue in `dir` and apply the stored arena size
+/// and flags to `conf`, or write them from `conf` for a new queue.
+fn open_meta(dir: &Path, mut conf: Config, read_only: bool) -> Result<Config> {
+    let path = dir.join(META_FILE);
+    if !path.exists() {
+        if read_only {
+            return Err(Error::Exist(path.to_string_lossy().to_string()));
+        }
+        if dir.join(INDEX_FILE).exists() {
+            return Err(Error::UnsupportedVersion(0));
+        }
+        write_meta(dir, &conf)?;
+        return Ok(conf);
+    }
+
+    let meta = fs::read(&path).map_err(Error::Io)?;
+    let invalid = || Error::InvalidFormat(path.to_string_lossy().to_string());
+    if meta.len() != META_FILE_SIZE || &meta[0..8] != META_MAGIC {
+        return Err(invalid());
+    }
+    let crc = transform_array_of_u8_to_u32(&meta[META_CRC_OFFSET..]);
+    if crc != crc32c::crc32c(&meta[..META_CRC_OFFSET]) {
+        return Err(invalid());
+    }
+    let version = transform_array_of_u8_to_u32(&meta[8..12]);
+    if version != FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let flags = transform_array_of_u8_to_u32(&meta[12..16]);
+    conf.arena_size = transform_array_of_u8_to_u64(&meta[16..24]) as usize;
+    conf.checksum = flags & META_FLAG_CHECKSUM != 0;
+    Ok(conf)
+}
+
+/// Write `meta.dat` through a temporary file, so it is either absent or
+/// complete.
+fn write_meta(dir: &Path, conf: &Config) -> Result<()> {
+    let mut flags = 0;
+    if conf.checksum {
+        flags |= META_FLAG_CHECKSUM;
+    }
+    let mut meta = Vec::with_capacity(META_FILE_SIZE);
+    meta.extend_from_slice(META_MAGIC);
+    meta.extend_from_slice(&transform_u32_to_array_of_u8(FORMAT_VERSION));
+    meta.extend_from_slice(&transform_u32_to_array_of_u8(flags));
+    meta.extend_from_slice(&transform_u64_to_array_of_u8(conf.arena_size as u64));
+    let crc = crc32c::crc32c(&meta);
+    meta.extend_from_slice(&transform_u32_to_array_of_u8(crc));
+
+    let tmp = dir.join(format!("{}.tmp", META_FILE));
+    fs::write(&tmp, &meta).map_err(Error::Io)?;
+    fs::rename(&tmp, dir.join(META_FILE)).map_err(Error::Io)
+}
+
+fn subscription_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let valid = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(Error::InvalidName(name.to_string()));
+    }
+    Ok(dir.join(format!("{}.{}", name, SUBSCRIPTION_EXT)))
+}
+
+/// Lock the named subscription, so only one handle at a time moves its
+/// cursor.
+fn subscription_lock(dir: &Path, name: &str) -> Result<DirLock> {
+    subscription_path(dir, name)?;
+    DirLock::exclusive(dir, &format!("{}.{}.lock", name, SUBSCRIPTION_EXT))
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Cursor {
+    generation: u64,
+    aid: usize,
+    offset: usize,
+    count: u64,
+    bytes: u64,
+}
+
+/// Decode the slot at `at`, `None` if it was never written or is torn.
+fn read_cursor_slot(arena: &Arena, at: usize) -> Option<Cursor> {
+    let slot = arena.bytes().get(at..at + CURSOR_SLOT_SIZE)?;
+    let crc = transform_array_of_u8_to_u32(&slot[CURSOR_CRC_OFFSET..CURSOR_CRC_OFFSET + 4]);
+    if crc != crc32c::crc32c(&slot[..CURSOR_CRC_OFFSET]) {
+        return None;
+    }
+    Some(Cursor {
+        generation: arena.read_u64_at(at)?,
+        aid: arena.read_u64_at(at + 8)? as usize,
+        offset: arena.read_u64_at(at + 16)? as usize,
+        count: arena.read_u64_at(at + 24)?,
+        bytes: arena.read_u64_at(at + 32)?,
+    })
+}
+
+/// Latest valid cursor of the pair starting at `base`. A pair that was
+/// never written reads as the start of the queue.
+fn read_cursor(arena: &Arena, base: usize) -> Cursor {
+    let a = read_cursor_slot(arena, base);
+    let b = read_cursor_slot(arena, base + CURSOR_SLOT_SIZE);
+    match (a, b) {
+        (Some(a), Some(b)) => if a.generation >= b.generation { a } else { b },
+        (Some(a), None) => a,
+        (None, Some(b)) => b,
+        (None, None) => Cursor::default(),
+    }
+}
+
+fn write_cursor(arena: &mut Arena, base: usize, aid: usize, offset: usize, count: u64, bytes: u64) -> Result<()> {
+    let a = read_cursor_slot(arena, base);
+    let b = read_cursor_slot(arena, base + CURSOR_SLOT_SIZE);
+    let (generation, at) = match (a, b) {
+        (Some(a), Some(b)) if a.generation >= b.generation => (a.generation + 1, base + CURSOR_SLOT_SIZE),
+        (Some(_), Some(b)) => (b.generation + 1, base),
+        (Some(a), None) => (a.generation + 1, base + CURSOR_SLOT_SIZE),
+        (None, Some(b)) => (b.generation + 1, base),
+        (None, None) => (1, base),
+    };
+
+    let mut slot = [0u8; CURSOR_SLOT_SIZE];
+    slot[0..8].copy_from_slice(&transform_u64_to_array_of_u8(generation));
+    slot[8..16].copy_from_slice(&transform_u64_to_array_of_u8(aid as u64));
+    slot[16..24].copy_from_slice(&transform_u64_to_array_of_u8(offset as u64));
+    slot[24..32].copy_from_slice(&transform_u64_to_array_of_u8(count));
+    slot[32..40].copy_from_slice(&transform_u64_to_array_of_u8(bytes));
+    let crc = crc32c::crc32c(&slot[..CURSOR_CRC_OFFSET]);
+    slot[CURSOR_CRC_OFFSET..CURSOR_CRC_OFFSET + 4].copy_from_slice(&transform_u32_to_array_of_u8(crc));
+    arena.write_bytes_at(at, &slot)
+}
 
 pub struct Index {
-    file: String,
     arena: Arena,
+    // head cursor of a named subscription, replaces the head slots
+    cursor: Option<Arena>,
 }
 
 impl Index {
@@ -438,97 +1060,225 @@ impl Index {
         let index_path = base.join(INDEX_FILE);
 
         Ok(Index {
-            file: index_path.as_path().to_string_lossy().into_owned(),
             arena: Arena::new(index_path, INDEX_FILE_SIZE)?,
+            cursor: None,
+        })
+    }
+
+    fn read_only(dir: &str) -> Result<Index> {
+        Ok(Index {
+            arena: Arena::open_read_only(Path::new(dir).join(INDEX_FILE))?,
+            cursor: None,
         })
     }
 
+    /// Index of the named subscription, with its cursor started at the
+    /// queue head the first time. The new cursor file is written aside and
+    /// renamed into place, so it never exists without its starting cursor.
+    fn subscription(dir: &str, name: &str) -> Result<Index> {
+        let mut index = Index::new(dir)?;
+        let cursor_path = subscription_path(Path::new(dir), name)?;
+        if !cursor_path.exists() {
+            let tmp = cursor_path.with_extension(format!("{}.tmp", SUBSCRIPTION_EXT));
+            let _ = fs::remove_file(&tmp);
+            let mut cursor = Arena::new(tmp.clone(), SUBSCRIPTION_FILE_SIZE)?;
+            let head = read_cursor(&index.arena, HEAD_CURSOR);
+            write_cursor(&mut cursor, HEAD_CURSOR, head.aid, head.offset, head.count, head.bytes)?;
+            cursor.flush()?;
+            drop(cursor);
+            fs::rename(&tmp, &cursor_path).map_err(Error::Io)?;
+        }
+        index.cursor = Some(Arena::new(cursor_path, SUBSCRIPTION_FILE_SIZE)?);
+        Ok(index)
+    }
+
     pub fn get_head_tuple(&self) -> Option<(usize, usize)> {
-        Some((
-            self.arena.read_u64_at_windows(0)? as usize,
-            self.arena.read_u64_at_windows(1)? as usize,
-        ))
+        let arena = self.cursor.as_ref().unwrap_or(&self.arena);
+        let cursor = read_cursor(arena, HEAD_CURSOR);
+        Some((cursor.aid, cursor.offset))
     }
 
-    fn set_head(&mut self, aid: usize, offset: usize) -> Result<()> {
-        self.arena.write_u64_at_windows(0, aid as u64)?;
-        self.arena.write_u64_at_windows(1, offset as u64)?;
-        Ok(())
+    /// Records and payload bytes consumed up to the head.
+    pub(crate) fn get_head_counts(&self) -> (u64, u64) {
+        let arena = self.cursor.as_ref().unwrap_or(&self.arena);
+        let cursor = read_cursor(arena, HEAD_CURSOR);
+        (cursor.count, cursor.bytes)
+    }
+
+    fn set_head(&mut self, aid: usize, offset: usize, count: u64, bytes: u64) -> Result<()> {
+        let arena = self.cursor.as_mut().unwrap_or(&mut self.arena);
+        write_cursor(arena, HEAD_CURSOR, aid, offset, count, bytes)
     }
 
     pub fn get_tail_tuple(&self) -> Option<(usize, usize)> {
-        Some((
-            self.arena.read_u64_at_windows(2)? as usize,
-            self.arena.read_u64_at_windows(3)? as usize,
-        ))
+        let cursor = read_cursor(&self.arena, TAIL_CURSOR);
+        Some((cursor.aid, cursor.offset))
+    }
+
+    /// Records and payload bytes pushed up to the tail.
+    pub(crate) fn get_tail_counts(&self) -> (u64, u64) {
+        let cursor = read_cursor(&self.arena, TAIL_CURSOR);
+        (cursor.count, cursor.bytes)
+    }
+
+    fn set_tail(&mut self, aid: usize, offset: usize, count: u64, bytes: u64) -> Result<()> {
+        write_cursor(&mut self.arena, TAIL_CURSOR, aid, offset, count, bytes)
+    }
+
+    /// Make `pos` the tail seen by `published_tail` and wake waiting
+    /// consumers.
+    fn publish_tail(&self, pos: u64) {
+        self.arena.atomic_u64(PUBLISHED_TAIL).store(pos + 1, Ordering::Release);
+        let seq = self.notify_seq();
+        seq.fetch_add(1, Ordering::SeqCst);
+        if self.notify_waiters().load(Ordering::SeqCst) > 0 {
+            process::wake_all(seq);
+        }
     }
 
-    fn set_tail(&mut self, aid: usize, offset: usize) -> Result<()> {
-        self.arena.write_u64_at_windows(2, aid as u64)?;
-        self.arena.write_u64_at_windows(3, offset as u64)?;
+    pub(crate) fn published_tail(&self) -> Option<u64> {
+        // an index written before the word existed, mapped read-only
+        if self.arena.bytes().len() < INDEX_FILE_SIZE {
+            return None;
+        }
+        match self.arena.atomic_u64(PUBLISHED_TAIL).load(Ordering::Acquire) {
+            0 => None,
+            v => Some(v - 1),
+        }
+    }
+
+    pub(crate) fn notify_seq(&self) -> &AtomicU32 {
+        self.arena.atomic_u32(NOTIFY_SEQ)
+    }
+
+    pub(crate) fn notify_waiters(&self) -> &AtomicU32 {
+        self.arena.atomic_u32(NOTIFY_WAITERS)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.arena.flush()?;
+        if let Some(cursor) = &self.cursor {
+            cursor.flush()?;
+        }
         Ok(())
     }
 }
 
+enum Map {
+    ReadWrite(MmapMut),
+    ReadOnly(Mmap),
+}
+
 pub struct Arena {
-    mmap: MmapMut,
+    mmap: Map,
+    // start of the mapping, taken from the writable map when there is one
+    // so the atomics below may store through it
+    ptr: *mut u8,
 }
 
+// `ptr` points into `mmap`, which moves with the arena
+unsafe impl Send for Arena {}
+
 impl Arena {
     pub fn new(path: PathBuf, size: usize) -> Result<Arena> {
         match OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&path) {
-            Err(e) => {
-                return Err(Error::Io(e));
-            }
+            Err(e) => Err(Error::Io(e)),
             Ok(file) => {
                 if file.set_len(size as u64).is_err() {
                     return Err(Error::OpenFileWithLength(path.to_string_lossy().to_string(), size));
                 }
-                Ok(Arena {
-                    mmap: unsafe { MmapMut::map_mut(&file).expect("mmap index.dat error") }
-                })
+                let mut mmap = unsafe { MmapMut::map_mut(&file).expect("mmap index.dat error") };
+                let ptr = mmap.as_mut_ptr();
+                Ok(Arena { mmap: Map::ReadWrite(mmap), ptr })
             }
         }
     }
 
-    pub fn read_u64_at(&self, offsize: usize) -> Option<u64> {
-        read_u64(&self.mmap, offsize)
+    /// Map an existing file read-only, without creating or resizing it.
+    pub fn open_read_only(path: PathBuf) -> Result<Arena> {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::Exist(path.to_string_lossy().to_string()));
+            }
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let mmap = unsafe { MmapOptions::new().map(&file) }.map_err(Error::Io)?;
+        // only ever loaded from, see `atomic_u64`
+        let ptr = mmap.as_ptr() as *mut u8;
+        Ok(Arena { mmap: Map::ReadOnly(mmap), ptr })
+    }
+
+    fn open(path: PathBuf, size: usize, read_only: bool) -> Result<Arena> {
+        if read_only {
+            Arena::open_read_only(path)
+        } else {
+            Arena::new(path, size)
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        match &self.mmap {
+            Map::ReadWrite(mmap) => mmap,
+            Map::ReadOnly(mmap) => mmap,
+        }
     }
 
-    pub fn read_u64_at_windows(&self, win: usize) -> Option<u64> {
-        self.read_u64_at(win * 8)
+    pub(crate) fn bytes_mut(&mut self) -> Result<&mut [u8]> {
+        match &mut self.mmap {
+            Map::ReadWrite(mmap) => Ok(mmap),
+            Map::ReadOnly(_) => Err(Error::ReadOnly),
+        }
+    }
+
+    pub fn read_u64_at(&self, offsize: usize) -> Option<u64> {
+        read_u64(self.bytes(), offsize)
     }
 
     pub fn write_u64_at(&mut self, offsize: usize, v: u64) -> Result<()> {
-        write_u64(&mut self.mmap, offsize, v)
+        write_u64(self.bytes_mut()?, offsize, v)
     }
 
     pub fn write_bytes_at(&mut self, offsize: usize, bytes: &[u8]) -> Result<()> {
-        write_bytes(&mut self.mmap, offsize, bytes)
+        write_bytes(self.bytes_mut()?, offsize, bytes)
     }
 
-    pub fn write_u64_at_windows(&mut self, win: usize, v: u64) -> Result<()> {
-        self.write_u64_at(win * 8, v)
+    pub fn flush(&self) -> Result<()> {
+        match &self.mmap {
+            Map::ReadWrite(mmap) => mmap.flush().map_err(Error::Io),
+            Map::ReadOnly(_) => Ok(()),
+        }
     }
 
-    pub fn flush(&mut self) -> Result<()> {
-        match self.mmap.flush() {
-            Ok(v) => v,
-            Err(_) => {
-                ()
-            }
+    pub fn flush_range(&self, offset: usize, len: usize) -> Result<()> {
+        match &self.mmap {
+            Map::ReadWrite(mmap) => mmap.flush_range(offset, len).map_err(Error::Io),
+            Map::ReadOnly(_) => Ok(()),
         }
-        Ok(())
+    }
+
+    /// The word at `offset` as an atomic. The mapping is page aligned and
+    /// outlives the borrow, and every access to these bytes goes through
+    /// atomics. Words of a read-only mapping must only be loaded.
+    fn atomic_u64(&self, offset: usize) -> &AtomicU64 {
+        assert!(offset & 7 == 0 && offset + 8 <= self.bytes().len());
+        unsafe { &*(self.ptr.add(offset) as *const AtomicU64) }
+    }
+
+    fn atomic_u32(&self, offset: usize) -> &AtomicU32 {
+        assert!(offset & 3 == 0 && offset + 4 <= self.bytes().len());
+        unsafe { &*(self.ptr.add(offset) as *const AtomicU32) }
     }
 }
 
 impl fmt::Display for Arena {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", &self.mmap[..])
+        write!(f, "{:?}", self.bytes())
     }
 }
 
@@ -544,8 +1294,8 @@ mod tests {
         fs::create_dir_all(PathBuf::from("/tmp/oo0o0o")).expect("failed to create dir");
 
         let mut qi = Index::new("/tmp/oo0o0o").expect("failed to open the 1");
-        qi.set_head(1, 3);
-        qi.set_tail(1, 4);
+        qi.set_head(1, 3, 0, 0).unwrap();
+        qi.set_tail(1, 4, 0, 0).unwrap();
 
         let (head_aid, head_offset) = qi.get_head_tuple().expect("failed to open the 1");
         let (tail_aid, tail_offset) = qi.get_tail_tuple().expect("failed to open the 1");
@@ -556,6 +1306,32 @@ mod tests {
         assert_eq!(tail_offset, 4);
     }
 
+    #[test]
+    fn test_index_torn_write() {
+        use crate::bigqueue::{Index, TAIL_CURSOR, CURSOR_SLOT_SIZE};
+        use std::fs;
+
+        let dir = "/tmp/bigqueue_test_torn_index";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let _ = fs::remove_file(format!("{}/index.dat", dir));
+
+        let mut qi = Index::new(dir).unwrap();
+        assert_eq!(qi.get_tail_tuple(), Some((0, 0)));
+        qi.set_tail(2, 100, 0, 0).unwrap();
+        qi.set_tail(3, 8, 0, 0).unwrap();
+        assert_eq!(qi.get_tail_tuple(), Some((3, 8)));
+
+        // the second update went to the second slot, tear it
+        qi.arena.write_u64_at(TAIL_CURSOR + CURSOR_SLOT_SIZE + 16, 9).unwrap();
+        assert_eq!(qi.get_tail_tuple(), Some((2, 100)));
+        assert_eq!(qi.get_head_tuple(), Some((0, 0)));
+
+        // the next update overwrites the torn slot
+        qi.set_tail(4, 0, 0, 0).unwrap();
+        let qi = Index::new(dir).unwrap();
+        assert_eq!(qi.get_tail_tuple(), Some((4, 0)));
+    }
+
     #[test]
     fn test_arena() {
         use crate::bigqueue::Arena;
@@ -566,12 +1342,297 @@ mod tests {
                 panic!("")
             }
         };
-        t.write_u64_at(0, 100u64);
-        t.write_u64_at(8, 10u64);
+        t.write_u64_at(0, 100u64).unwrap();
+        t.write_u64_at(8, 10u64).unwrap();
         t.flush().unwrap();
 //        println!("{:?}",t.read_u64_at_windows(0));
 //        println!("{}",t.read_u64_at(0));
         println!("{}", t);
 //        assert_eq!(content, &mmap[..])
     }
+
+    #[test]
+    fn test_push_pop_across_arenas() {
+        use crate::{BigQueue, Config};
+        use std::fs;
+
+        let dir = "/tmp/bigqueue_test_arenas";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 32;
+        let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+
+        let big = vec![7u8; 100];
+        for i in 0..20u8 {
+            q.push(&[i; 13]).unwrap();
+        }
+        q.push(&big).unwrap();
+        q.push(b"last").unwrap();
+
+        for i in 0..20u8 {
+            assert_eq!(q.peek().unwrap(), vec![i; 13]);
+            assert_eq!(q.pop().unwrap(), vec![i; 13]);
+        }
+        q.dequeue().unwrap();
+        assert_eq!(q.pop().unwrap(), b"last".to_vec());
+
+        q.push(&big).unwrap();
+        q.push(b"in place").unwrap();
+        assert!(q.pop_with(|data| data == &big[..]).unwrap());
+        assert_eq!(q.pop_with(|data| data.len()).unwrap(), 8);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn test_checksum() {
+        use crate::{BigQueue, Config, Error};
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom, Write};
+        use std::fs;
+
+        let dir = "/tmp/bigqueue_test_checksum";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 40;
+        conf.checksum = true;
+        let mut q = BigQueue::with_config(dir, true, conf.clone()).unwrap();
+
+        for i in 0..30u8 {
+            q.push(&vec![i; i as usize]).unwrap();
+        }
+        for i in 0..30u8 {
+            if i % 2 == 0 {
+                assert_eq!(q.pop().unwrap(), vec![i; i as usize]);
+            } else {
+                assert!(q.pop_with(|data| data == &vec![i; i as usize][..]).unwrap());
+            }
+        }
+
+        q.push(b"0123456789").unwrap();
+        q.push(b"abcdefghij").unwrap();
+        let (aid, offset) = (q.head_aid, q.head_offset);
+        let mut file = OpenOptions::new().write(true)
+            .open(format!("{}/arena_{}.dat", dir, aid)).unwrap();
+        file.seek(SeekFrom::Start((offset + 8 + 3) as u64)).unwrap();
+        file.write_all(b"X").unwrap();
+        file.sync_all().unwrap();
+
+        for _ in 0..2 {
+            match q.peek() {
+                Err(Error::Corrupted { aid: a, offset: o }) => assert_eq!((a, o), (aid, offset)),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        match q.pop() {
+            Err(Error::Corrupted { .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // a garbage length is caught before reading past the tail
+        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+        file.write_all(&u64::MAX.to_le_bytes()).unwrap();
+        file.sync_all().unwrap();
+        match q.pop_with(|data| data.len()) {
+            Err(Error::Corrupted { .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        q.dequeue().unwrap_err();
+        drop(q);
+
+        // reported where the header is, past the end of the head's arena
+        let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+        q.push(&[1; 24]).unwrap();
+        q.push(b"next").unwrap();
+        q.pop().unwrap();
+        assert_eq!((q.head_aid, q.head_offset), (0, 36));
+        let mut file = OpenOptions::new().write(true)
+            .open(format!("{}/arena_1.dat", dir)).unwrap();
+        file.seek(SeekFrom::Start(8 + 1)).unwrap();
+        file.write_all(b"X").unwrap();
+        file.sync_all().unwrap();
+        match q.peek() {
+            Err(Error::Corrupted { aid: 1, offset: 0 }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_durability() {
+        use crate::{BigQueue, Config, Durability};
+        use std::fs;
+        use std::thread;
+        use std::time::Duration;
+
+        let dir = "/tmp/bigqueue_test_durability";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        for durability in [Durability::Always, Durability::EveryN(3),
+                               Durability::Interval(Duration::from_millis(1))] {
+            let mut conf = Config::new();
+            conf.arena_size = 64;
+            conf.durability = durability;
+            let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+            for i in 0..40u8 {
+                if let Durability::Interval(interval) = durability {
+                    thread::sleep(interval * 2);
+                }
+                q.push(&[i; 9]).unwrap();
+                match durability {
+                    Durability::EveryN(n) => {
+                        assert_eq!(q.unsynced, (i as u64 + 1) % n);
+                        assert_eq!(q.synced_pos == q.tail_pos(), q.unsynced == 0);
+                    }
+                    // the interval has passed since the last push synced
+                    _ => assert_eq!((q.synced_pos, q.unsynced), (q.tail_pos(), 0)),
+                }
+            }
+            for i in 0..20u8 {
+                assert_eq!(q.pop().unwrap(), vec![i; 9]);
+            }
+            q.sync().unwrap();
+            assert_eq!(q.synced_pos, q.tail_pos());
+            assert_eq!(q.unsynced, 0);
+        }
+    }
+
+    #[test]
+    fn test_meta() {
+        use crate::{BigQueue, Config, Error};
+        use std::fs;
+
+        let dir = "/tmp/bigqueue_test_meta";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        conf.checksum = true;
+        let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+        q.push(b"stored with 64 byte arenas").unwrap();
+        drop(q);
+
+        // the stored arena size and flags win over the passed config
+        let mut q = BigQueue::new(dir, false).unwrap();
+        assert_eq!(q.config().arena_size, 64);
+        assert!(q.config().checksum);
+        assert_eq!(q.pop().unwrap(), b"stored with 64 byte arenas".to_vec());
+        drop(q);
+
+        let meta = PathBuf::from(dir).join("meta.dat");
+        let mut bytes = fs::read(&meta).unwrap();
+        bytes[8] = 9;
+        fs::write(&meta, &bytes).unwrap();
+        match BigQueue::new(dir, false) {
+            Err(Error::InvalidFormat(_)) => {}
+            Err(e) => panic!("unexpected {:?}", e),
+            Ok(_) => panic!("opened a queue with a bad superblock"),
+        }
+
+        // an index without metadata is the old layout
+        fs::remove_file(&meta).unwrap();
+        match BigQueue::new(dir, false) {
+            Err(Error::UnsupportedVersion(0)) => {}
+            Err(e) => panic!("unexpected {:?}", e),
+            Ok(_) => panic!("opened a queue in the old layout"),
+        }
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        use crate::{BigQueue, Config, Error};
+        use std::fs;
+
+        let dir = "/tmp/bigqueue_test_read_only";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+        for i in 0..10u8 {
+            q.push(&[i; 20]).unwrap();
+        }
+
+        // the owner keeps going while the inspector follows
+        let mut r = BigQueue::open_read_only(dir).unwrap();
+        assert_eq!(r.config().arena_size, 64);
+        assert_eq!(r.peek().unwrap(), vec![0; 20]);
+        for _ in 0..5 {
+            q.pop().unwrap();
+        }
+        assert_eq!(r.peek().unwrap(), vec![5; 20]);
+        match r.pop() {
+            Err(Error::ReadOnly) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(r.push(b"nope").is_err());
+        q.shrink();
+        drop(r);
+
+        for i in 5..10u8 {
+            assert_eq!(q.pop().unwrap(), vec![i; 20]);
+        }
+        drop(q);
+        let before: Vec<_> = fs::read_dir(dir).unwrap().flatten().map(|e| e.file_name()).collect();
+        drop(BigQueue::open_read_only(dir).unwrap());
+        let after: Vec<_> = fs::read_dir(dir).unwrap().flatten().map(|e| e.file_name()).collect();
+        assert_eq!(before, after);
+        assert!(BigQueue::open_read_only("/tmp/bigqueue_test_read_only_missing").is_err());
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        use crate::{BigQueue, Config};
+        use std::fs;
+
+        let dir = "/tmp/bigqueue_test_seq";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        let mut q = BigQueue::with_config(dir, true, conf.clone()).unwrap();
+        for i in 0..5u64 {
+            assert_eq!(q.push(&[i as u8; 20]).unwrap(), i);
+        }
+        assert_eq!(q.next_seq(), 0);
+        q.pop().unwrap();
+        assert_eq!(q.pop_ref().unwrap().seq(), 1);
+        let delivery = q.receive().unwrap();
+        assert_eq!((delivery.record_seq(), q.next_seq()), (2, 3));
+        q.nack(&delivery).unwrap();
+        let again = q.receive().unwrap();
+        assert_eq!(again.record_seq(), 2);
+        drop(q);
+
+        // numbering carries on after a reopen
+        let mut q = BigQueue::with_config(dir, false, conf).unwrap();
+        assert_eq!(q.next_seq(), 3);
+        assert_eq!(q.receive().unwrap().record_seq(), 2);
+        assert_eq!(q.push(b"more").unwrap(), 5);
+        q.pop().unwrap();
+        assert_eq!(q.next_seq(), 4);
+    }
+
+    #[test]
+    fn test_push_vectored() {
+        use crate::{BigQueue, Config};
+        use std::fs;
+        use std::io::IoSlice;
+
+        let dir = "/tmp/bigqueue_test_vectored";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        conf.checksum = true;
+        let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+        let header = [7u8; 12];
+        for i in 0..10u8 {
+            // bodies of every size straddle the arena ends at different parts
+            let body = vec![i; 5 * i as usize];
+            let seq = q.push_vectored(&[IoSlice::new(&header), IoSlice::new(&[]), IoSlice::new(&body)]).unwrap();
+            assert_eq!(seq, i as u64);
+        }
+        q.push_vectored(&[]).unwrap();
+        for i in 0..10u8 {
+            let mut expected = header.to_vec();
+            expected.extend(vec![i; 5 * i as usize]);
+            assert_eq!(q.pop().unwrap(), expected);
+        }
+        assert_eq!(q.pop().unwrap(), Vec::<u8>::new());
+        assert!(q.is_empty());
+    }
 }
@@ -0,0 +1,60 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Upgrade a queue directory written by an older version of bigqueue.
+//!
+//! ```text
+//! bigqueue-migrate [--legacy-arena-size N] [--arena-size N] [--checksum] <src> [<dst>]
+//! ```
+//!
+//! Without `<dst>` the queue in `<src>` is upgraded in place. Run it again
+//! with the same arguments to resume an interrupted migration.
+
+use std::{env, process};
+
+use bigqueue::Config;
+
+const DEFAULT_ARENA_SIZE: usize = 128 * 1024 * 1024;
+
+fn usage() -> ! {
+    eprintln!("usage: bigqueue-migrate [--legacy-arena-size N] [--arena-size N] [--checksum] <src> [<dst>]");
+    process::exit(2);
+}
+
+fn size_arg(value: Option<String>) -> usize {
+    value.and_then(|v| v.parse().ok()).unwrap_or_else(|| usage())
+}
+
+fn main() {
+    let mut legacy_arena_size = DEFAULT_ARENA_SIZE;
+    let mut conf = Config::new();
+    let mut dirs = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--legacy-arena-size" => legacy_arena_size = size_arg(args.next()),
+            "--arena-size" => conf.arena_size = size_arg(args.next()),
+            "--checksum" => conf.checksum = true,
+            "-h" | "--help" => usage(),
+            _ => dirs.push(arg),
+        }
+    }
+
+    let result = match dirs.as_slice() {
+        [src] => bigqueue::upgrade(src, legacy_arena_size, conf),
+        [src, dst] => bigqueue::migrate(src, dst, legacy_arena_size, conf),
+        _ => usage(),
+    };
+    match result {
+        Ok(report) => println!("migrated {} records, {} bytes", report.records, report.bytes),
+        Err(e) => {
+            eprintln!("migration failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,496 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::path::PathBuf;
+use std::thread;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, fence, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{BigQueue, Config, Error, Result};
+use crate::lock::DirLock;
+
+/// Cursors published from the senders to the receiver, as byte positions
+/// into the queue. Senders claim space by moving `reserved` and publish
+/// `tail` in reservation order. A sender whose write fails sets `poisoned`
+/// to the start of its span, which is never published.
+struct Shared {
+    tail: AtomicU64,
+    reserved: AtomicU64,
+    poisoned: AtomicU64,
+
+    senders: AtomicUsize,
+    waiting: AtomicBool,
+    lock: Mutex<()>,
+    cond: Condvar,
+
+    // to open the handle of every cloned sender
+    dir: PathBuf,
+    config: Config,
+    dir_lock: Arc<DirLock>,
+}
+
+impl Shared {
+    /// Park the receiver until the tail moves past `seen`, the sender goes
+    /// away or `deadline` passes. Returns false on timeout.
+    fn wait(&self, seen: u64, deadline: Option<Instant>) -> bool {
+        let guard = self.lock.lock().unwrap();
+        self.waiting.store(true, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+
+        let mut timed_out = false;
+        if self.tail.load(Ordering::SeqCst) == seen && !self.is_closed() && !self.is_poisoned(seen) {
+            match deadline {
+                None => {
+                    let _guard = self.cond.wait(guard).unwrap();
+                }
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        timed_out = true;
+                    } else {
+                        let (_guard, res) = self.cond.wait_timeout(guard, deadline - now).unwrap();
+                        timed_out = res.timed_out();
+                    }
+                }
+            }
+        }
+        self.waiting.store(false, Ordering::SeqCst);
+        !timed_out
+    }
+
+    fn is_closed(&self) -> bool {
+        self.senders.load(Ordering::SeqCst) == 0
+    }
+
+    /// Whether the span starting at `pos` or one before it will never be
+    /// published.
+    fn is_poisoned(&self, pos: u64) -> bool {
+        self.poisoned.load(Ordering::SeqCst) <= pos
+    }
+
+    /// Wake the receiver if it is parked in `wait`.
+    fn notify(&self) {
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::SeqCst) {
+            let _guard = self.lock.lock().unwrap();
+            self.cond.notify_one();
+        }
+    }
+}
+
+/// A reading handle whose tail is published by another one, the receive
+/// loop shared by `Receiver` and `process::Consumer`.
+pub(crate) trait TailFollower {
+    fn queue(&mut self) -> &mut BigQueue;
+
+    /// Adopt the tail last published by the writer.
+    fn follow_tail(&mut self);
+
+    /// Whether the writer is gone, read before the tail is followed.
+    fn is_closed(&self) -> bool {
+        false
+    }
+
+    /// Fail if the queue is drained and no more records will come,
+    /// `closed` being what `is_closed` returned.
+    fn check_drained(&mut self, _closed: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Park until the tail moves past `seen`. Returns false once `deadline`
+    /// passed.
+    fn wait(&self, seen: u64, deadline: Option<Instant>) -> bool;
+
+    fn try_recv_followed<F, R>(&mut self, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> R {
+        self.follow_tail();
+        self.check_drained(false)?;
+        self.queue().pop_with(f)
+    }
+
+    fn recv_deadline_with<F, R>(&mut self, deadline: Option<Instant>, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> R {
+        loop {
+            // read the flag first so a record pushed right before the close is not lost
+            let closed = self.is_closed();
+            self.follow_tail();
+            if !self.queue().is_empty() {
+                return self.queue().pop_with(f);
+            }
+            self.check_drained(closed)?;
+            let seen = self.queue().tail_pos();
+            if !self.wait(seen, deadline) {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+}
+
+pub fn channel(dir: &str, reset: bool) -> Result<(Sender, Receiver)> {
+    channel_with_config(dir, reset, Config::new())
+}
+
+/// Like `channel`, but opens both halves with `conf`.
+///
+/// Each half owns its own `BigQueue` over `dir`, so the writer thread and
+/// the reader thread never touch the same arena mappings. Records become
+/// visible to the receiver once the sender publishes the new tail.
+pub fn channel_with_config(dir: &str, reset: bool, conf: Config) -> Result<(Sender, Receiver)> {
+    let tx = BigQueue::with_config(dir, reset, conf)?;
+    tx.check_writable()?;
+    let rx = tx.reopen()?;
+    let shared = Arc::new(Shared {
+        tail: AtomicU64::new(tx.tail_pos()),
+        reserved: AtomicU64::new(tx.tail_pos()),
+        poisoned: AtomicU64::new(u64::MAX),
+        senders: AtomicUsize::new(1),
+        waiting: AtomicBool::new(false),
+        lock: Mutex::new(()),
+        cond: Condvar::new(),
+        dir: tx.dir.clone(),
+        config: tx.config.clone(),
+        dir_lock: tx.lock.clone(),
+    });
+    Ok((Sender::new(Some(tx), shared.clone()), Receiver::new(rx, shared)))
+}
+
+/// The producing half of a channel. Clones are producers of their own and
+/// can be moved to different threads to push concurrently.
+pub struct Sender {
+    // opened on the first enqueue for a clone
+    queue: Option<BigQueue>,
+    shared: Arc<Shared>,
+}
+
+pub struct Receiver {
+    queue: BigQueue,
+    shared: Arc<Shared>,
+}
+
+impl Sender {
+    fn new(queue: Option<BigQueue>, shared: Arc<Shared>) -> Sender {
+        Sender { queue, shared }
+    }
+
+    /// Append a record. Producers reserve their span of the queue up front
+    /// and copy in parallel; a record becomes visible once every record
+    /// reserved before it has been written.
+    ///
+    /// A producer whose write fails or panics poisons the channel: the
+    /// producers that reserved after it fail with `Error::Poisoned` instead
+    /// of waiting for it, and so does the receiver once it has drained the
+    /// records before it.
+    ///
+    /// A clone maps its own arenas on its first `enqueue`, which fails
+    /// without touching the channel if they cannot be opened.
+    ///
+    /// Returns the record's sequence number, see `BigQueue::push`.
+    pub fn enqueue(&mut self, elem: &[u8]) -> Result<u64> {
+        if self.queue.is_none() {
+            let shared = &self.shared;
+            self.queue = Some(BigQueue::open_handle(&shared.dir, shared.config.clone(), shared.dir_lock.clone())?);
+        }
+        let queue = self.queue.as_mut().expect("sender handle is open");
+
+        let mut start = self.shared.reserved.load(Ordering::Relaxed);
+        let end = loop {
+            let end = queue.record_end(start, elem.len());
+            match self.shared.reserved.compare_exchange_weak(
+                start, end, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break end,
+                Err(current) => start = current,
+            }
+        };
+
+        let mut guard = PoisonGuard { shared: &self.shared, start, armed: true };
+        if self.shared.is_poisoned(start) {
+            return Err(Error::Poisoned);
+        }
+        queue.seek_tail(start)?;
+        queue.append(elem)?;
+        debug_assert_eq!(queue.tail_pos(), end);
+
+        // publish in reservation order, unless a write before ours failed
+        let mut spins = 0u32;
+        while self.shared.tail.load(Ordering::Acquire) != start {
+            if self.shared.is_poisoned(start) {
+                return Err(Error::Poisoned);
+            }
+            spins += 1;
+            if spins < 64 {
+                std::hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+        // publish even if the sync failed, later producers are waiting on us
+        guard.armed = false;
+        let committed = queue.commit_tail();
+        self.shared.tail.store(end, Ordering::Release);
+        self.shared.notify();
+        committed.map(|pushed| pushed - 1)
+    }
+}
+
+/// Poisons the channel from `start` on unless disarmed, so a write that
+/// returns early or unwinds does not leave later producers waiting.
+struct PoisonGuard<'a> {
+    shared: &'a Shared,
+    start: u64,
+    armed: bool,
+}
+
+impl<'a> Drop for PoisonGuard<'a> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.shared.poisoned.fetch_min(self.start, Ordering::SeqCst);
+            self.shared.notify();
+        }
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Sender {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Sender::new(None, self.shared.clone())
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.notify();
+        }
+    }
+}
+
+impl Receiver {
+    fn new(queue: BigQueue, shared: Arc<Shared>) -> Receiver {
+        Receiver { queue, shared }
+    }
+
+    /// Sequence number of the record the next receive returns.
+    pub fn next_seq(&self) -> u64 {
+        self.queue.next_seq()
+    }
+
+    pub fn dequeue(&mut self) -> Result<()> {
+        self.queue.sync_tail(self.shared.tail.load(Ordering::Acquire));
+        self.check_poisoned()?;
+        self.queue.dequeue()
+    }
+
+    /// Pop the next record without blocking, `Error::QueueEmpty` if there
+    /// is none yet and `Error::Poisoned` if none will come, see
+    /// `Sender::enqueue`.
+    pub fn try_recv(&mut self) -> Result<Vec<u8>> {
+        self.try_recv_with(|data| data.to_vec())
+    }
+
+    /// Pop the next record, parking the thread until the sender pushes one.
+    /// Fails with `Error::Disconnected` once the sender is dropped and the
+    /// queue is drained, and with `Error::Poisoned` after a failed write.
+    pub fn recv(&mut self) -> Result<Vec<u8>> {
+        self.recv_deadline_with(None, |data| data.to_vec())
+    }
+
+    /// Like `recv`, but gives up with `Error::Timeout` after `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        self.recv_deadline_with(Some(Instant::now() + timeout), |data| data.to_vec())
+    }
+
+    /// Zero-copy `try_recv`: `f` sees the payload in place, before the head
+    /// moves past it.
+    pub fn try_recv_with<F, R>(&mut self, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> R {
+        self.try_recv_followed(f)
+    }
+
+    /// Zero-copy `recv`.
+    pub fn recv_with<F, R>(&mut self, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> R {
+        self.recv_deadline_with(None, f)
+    }
+
+    /// Zero-copy `recv_timeout`.
+    pub fn recv_timeout_with<F, R>(&mut self, timeout: Duration, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> R {
+        self.recv_deadline_with(Some(Instant::now() + timeout), f)
+    }
+
+    /// Fail with `Error::Poisoned` once every record published before a
+    /// failed write has been received.
+    fn check_poisoned(&self) -> Result<()> {
+        if self.queue.is_empty() && self.shared.is_poisoned(self.queue.tail_pos()) {
+            return Err(Error::Poisoned);
+        }
+        Ok(())
+    }
+}
+
+impl TailFollower for Receiver {
+    fn queue(&mut self) -> &mut BigQueue {
+        &mut self.queue
+    }
+
+    fn follow_tail(&mut self) {
+        self.queue.sync_tail(self.shared.tail.load(Ordering::Acquire));
+    }
+
+    fn is_closed(&self) -> bool {
+        self.shared.is_closed()
+    }
+
+    fn check_drained(&mut self, closed: bool) -> Result<()> {
+        self.check_poisoned()?;
+        if closed {
+            return Err(Error::Disconnected);
+        }
+        Ok(())
+    }
+
+    fn wait(&self, seen: u64, deadline: Option<Instant>) -> bool {
+        self.shared.wait(seen, deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use crate::Config;
+
+    #[test]
+    fn test_spsc_across_arenas() {
+        let dir = "/tmp/bigqueue_test_spsc";
+        fs::create_dir_all(dir).expect("failed to create dir");
+
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        let (mut tx, mut rx) = super::channel_with_config(dir, true, conf).unwrap();
+
+        let total = 10000;
+        let t = thread::spawn(move || {
+            for i in 0..total {
+                tx.enqueue(format!("message-{}", i).as_bytes()).unwrap();
+            }
+        });
+
+        let mut count = 0;
+        while count < total {
+            if rx.dequeue().is_ok() {
+                count += 1;
+            } else {
+                thread::yield_now();
+            }
+        }
+        t.join().unwrap();
+        assert!(rx.dequeue().is_err());
+    }
+
+    #[test]
+    fn test_recv_blocks_until_sent() {
+        use std::time::Duration;
+        use crate::Error;
+
+        let dir = "/tmp/bigqueue_test_recv";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let (mut tx, mut rx) = super::channel(dir, true).unwrap();
+
+        match rx.recv_timeout(Duration::from_millis(10)) {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let t = thread::spawn(move || {
+            for i in 0..1000u32 {
+                tx.enqueue(&i.to_le_bytes()).unwrap();
+            }
+        });
+        for i in 0..1000u32 {
+            if i % 2 == 0 {
+                assert_eq!(rx.recv().unwrap(), i.to_le_bytes().to_vec());
+            } else {
+                assert!(rx.recv_with(|data| data == &i.to_le_bytes()[..]).unwrap());
+            }
+        }
+        t.join().unwrap();
+
+        match rx.recv() {
+            Err(Error::Disconnected) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_multi_producer() {
+        let dir = "/tmp/bigqueue_test_mpsc";
+        fs::create_dir_all(dir).expect("failed to create dir");
+
+        let mut conf = Config::new();
+        conf.arena_size = 256;
+        let (tx, mut rx) = super::channel_with_config(dir, true, conf).unwrap();
+
+        let producers = 4u32;
+        let total = 2000u32;
+        let handles: Vec<_> = (0..producers).map(|p| {
+            let mut tx = tx.clone();
+            thread::spawn(move || {
+                for i in 0..total {
+                    let mut record = p.to_le_bytes().to_vec();
+                    record.extend_from_slice(&i.to_le_bytes());
+                    record.resize(8 + (i % 37) as usize, p as u8);
+                    tx.enqueue(&record).unwrap();
+                }
+            })
+        }).collect();
+        drop(tx);
+
+        let mut next = vec![0u32; producers as usize];
+        while let Ok(record) = rx.recv() {
+            let mut word = [0u8; 4];
+            word.copy_from_slice(&record[0..4]);
+            let p = u32::from_le_bytes(word) as usize;
+            word.copy_from_slice(&record[4..8]);
+            assert_eq!(u32::from_le_bytes(word), next[p]);
+            assert_eq!(record.len(), 8 + (next[p] % 37) as usize);
+            next[p] += 1;
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert!(next.iter().all(|&n| n == total));
+    }
+
+    #[test]
+    fn test_failed_write_poisons() {
+        use crate::Error;
+
+        let dir = "/tmp/bigqueue_test_poisoned";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        let (mut tx, mut rx) = super::channel_with_config(dir, true, conf).unwrap();
+        let mut other = tx.clone();
+
+        other.enqueue(&[2; 10]).unwrap();
+        tx.enqueue(&[1; 70]).unwrap();
+        assert_eq!(rx.recv().unwrap(), vec![2; 10]);
+        assert_eq!(rx.recv().unwrap(), vec![1; 70]);
+
+        // `other` still maps the first arena and cannot open the tail's
+        fs::remove_dir_all(dir).unwrap();
+        assert!(other.enqueue(b"lost").is_err());
+        for result in [tx.enqueue(b"after").map(|_| ()), rx.recv().map(|_| ())] {
+            match result {
+                Err(Error::Poisoned) => {}
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::{BigQueue, Error, Result};
+use crate::{transform_array_of_u8_to_u64, transform_u64_to_array_of_u8};
+
+// `deliveries.dat`, next to `index.dat`, lists the records delivered and not
+// acknowledged yet, oldest first:
+//
+// entry: position u64 | records consumed before it u64 | bytes consumed before it u64
+const DELIVERIES_FILE: &str = "deliveries.dat";
+const DELIVERY_ENTRY_SIZE: usize = 24;
+
+/// A record handed out by `BigQueue::receive`. It stays in the queue until
+/// it is acknowledged with `BigQueue::ack`.
+#[derive(Debug)]
+pub struct Delivery {
+    seq: u64,
+    record_seq: u64,
+    pos: u64,
+    data: Vec<u8>,
+}
+
+impl Delivery {
+    /// Delivery sequence number. Every delivery, including a redelivery of
+    /// the same record, gets a new one.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Sequence number of the delivered record, see `BigQueue::push`. The
+    /// same for every delivery of a record.
+    pub fn record_seq(&self) -> u64 {
+        self.record_seq
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub(crate) struct Pending {
+    seq: u64,
+    deadline: Instant,
+    // records and payload bytes consumed before this record
+    popped: u64,
+    popped_bytes: u64,
+}
+
+impl Pending {
+    pub(crate) fn popped(&self) -> (u64, u64) {
+        (self.popped, self.popped_bytes)
+    }
+}
+
+/// Read the pending deliveries persisted in `dir` as their positions and
+/// the records and bytes consumed before them, oldest first.
+pub(crate) fn read_pending(dir: &Path) -> Result<Vec<(u64, u64, u64)>> {
+    let path = dir.join(DELIVERIES_FILE);
+    let data = match fs::read(&path) {
+        Ok(v) => v,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    if data.len() % DELIVERY_ENTRY_SIZE != 0 {
+        return Err(Error::InvalidFormat(path.to_string_lossy().to_string()));
+    }
+    Ok(data.chunks(DELIVERY_ENTRY_SIZE).map(|entry| {
+        let word = |i: usize| transform_array_of_u8_to_u64(&entry[i * 8..i * 8 + 8]);
+        (word(0), word(1), word(2))
+    }).collect())
+}
+
+impl BigQueue {
+    /// Hand out the next record without consuming it. Records whose
+    /// visibility timeout expired or that were `nack`ed are delivered again
+    /// before new ones.
+    ///
+    /// Unacknowledged records are persisted next to the index and are
+    /// delivered again first after a reopen. Until then they keep their
+    /// arenas from being removed by `shrink`.
+    pub fn receive(&mut self) -> Result<Delivery> {
+        self.check_writable()?;
+        let now = Instant::now();
+        let seq = self.next_delivery;
+        let deadline = now + self.config.visibility_timeout;
+
+        let expired = self.deliveries.iter()
+            .find(|(_, p)| p.deadline <= now)
+            .map(|(&pos, _)| pos);
+        let (pos, data, (popped, popped_bytes)) = match expired {
+            Some(pos) => (pos, self.read_at(pos)?.0, self.deliveries[&pos].popped()),
+            None => {
+                let pos = self.head_pos();
+                let popped = (self.popped, self.popped_bytes);
+                let data = self.read_next()?;
+                (pos, data, popped)
+            }
+        };
+
+        self.next_delivery += 1;
+        let redelivery = self.deliveries
+            .insert(pos, Pending { seq, deadline, popped, popped_bytes })
+            .is_some();
+        if !redelivery {
+            // listed before the head moves past it, so it is never lost
+            self.write_pending()?;
+            self.persist_head(1)?;
+        }
+        Ok(Delivery { seq, record_seq: popped, pos, data })
+    }
+
+    /// Acknowledge a delivery, removing its record for good. A delivery
+    /// whose record was delivered again since is no longer known.
+    pub fn ack(&mut self, delivery: &Delivery) -> Result<()> {
+        self.check_writable()?;
+        match self.deliveries.get(&delivery.pos) {
+            Some(pending) if pending.seq == delivery.seq => {
+                self.deliveries.remove(&delivery.pos);
+            }
+            _ => return Err(Error::UnknownDelivery(delivery.seq)),
+        }
+        self.write_pending()
+    }
+
+    /// Give a delivery back, making its record available to the next
+    /// `receive` right away.
+    pub fn nack(&mut self, delivery: &Delivery) -> Result<()> {
+        self.check_writable()?;
+        match self.deliveries.get_mut(&delivery.pos) {
+            Some(pending) if pending.seq == delivery.seq => {
+                pending.deadline = Instant::now();
+                Ok(())
+            }
+            _ => Err(Error::UnknownDelivery(delivery.seq)),
+        }
+    }
+
+    /// Reload the deliveries left unacknowledged when the queue was last
+    /// closed, due for redelivery right away. Entries at or past the head
+    /// were never consumed and are dropped.
+    pub(crate) fn load_pending(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let head = self.head_pos();
+        for (pos, popped, popped_bytes) in read_pending(&self.dir)? {
+            if pos >= head {
+                continue;
+            }
+            let seq = self.next_delivery;
+            self.next_delivery += 1;
+            self.deliveries.insert(pos, Pending { seq, deadline: now, popped, popped_bytes });
+        }
+        Ok(())
+    }
+
+    /// Replace the persisted pending deliveries through a rename, so the
+    /// list is never torn.
+    pub(crate) fn write_pending(&self) -> Result<()> {
+        let mut data = Vec::with_capacity(self.deliveries.len() * DELIVERY_ENTRY_SIZE);
+        for (&pos, pending) in &self.deliveries {
+            for word in [pos, pending.popped, pending.popped_bytes] {
+                data.extend_from_slice(&transform_u64_to_array_of_u8(word));
+            }
+        }
+        let tmp = self.dir.join(format!("{}.tmp", DELIVERIES_FILE));
+        fs::write(&tmp, &data).map_err(Error::Io)?;
+        fs::rename(&tmp, self.dir.join(DELIVERIES_FILE)).map_err(Error::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{BigQueue, Config, Error};
+
+    #[test]
+    fn test_ack_and_redeliver() {
+        let dir = "/tmp/bigqueue_test_delivery";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        conf.visibility_timeout = Duration::from_millis(50);
+        let mut q = BigQueue::with_config(dir, true, conf.clone()).unwrap();
+        for i in 0..10u8 {
+            q.push(&[i; 12]).unwrap();
+        }
+
+        let first = q.receive().unwrap();
+        let second = q.receive().unwrap();
+        assert_eq!(first.data(), &[0; 12][..]);
+        assert_eq!(second.data(), &[1; 12][..]);
+
+        // a nacked record comes back before new ones
+        q.nack(&second).unwrap();
+        let again = q.receive().unwrap();
+        assert_eq!(again.data(), &[1; 12][..]);
+        assert!(again.seq() > second.seq());
+        q.ack(&again).unwrap();
+        match q.ack(&again) {
+            Err(Error::UnknownDelivery(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // an unacknowledged record comes back once its timeout expires
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(q.receive().unwrap().data(), &[0; 12][..]);
+
+        // after a reopen only the unacknowledged record comes again, records
+        // acknowledged or popped behind it do not
+        let third = q.receive().unwrap();
+        assert_eq!(third.data(), &[2; 12][..]);
+        q.ack(&third).unwrap();
+        assert_eq!(q.pop().unwrap(), vec![3; 12]);
+        drop(q);
+        let mut q = BigQueue::with_config(dir, false, conf).unwrap();
+        assert_eq!(q.len(), 6);
+        let first = q.receive().unwrap();
+        assert_eq!(first.data(), &[0; 12][..]);
+        assert_eq!(first.record_seq(), 0);
+        q.ack(&first).unwrap();
+        for i in 4..10u8 {
+            assert_eq!(q.receive().unwrap().data(), &[i; 12][..]);
+        }
+        assert!(q.receive().is_err());
+    }
+
+    #[test]
+    fn test_stale_ack() {
+        let dir = "/tmp/bigqueue_test_delivery_stale";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.visibility_timeout = Duration::from_millis(10);
+        let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+        q.push(b"record").unwrap();
+
+        // the first consumer's timeout expires and the record goes to another
+        let stale = q.receive().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        let current = q.receive().unwrap();
+        match q.ack(&stale) {
+            Err(Error::UnknownDelivery(seq)) => assert_eq!(seq, stale.seq()),
+            other => panic!("unexpected {:?}", other),
+        }
+        q.ack(&current).unwrap();
+        assert!(q.receive().is_err());
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::{BigQueue, Error, Result};
+use crate::bigqueue::record_crc;
+
+/// Iterator over the records of a queue from head to tail, created by
+/// `BigQueue::iter`. Yields `Err` once and stops at the first record that
+/// cannot be read.
+pub struct Iter<'a> {
+    queue: &'a mut BigQueue,
+    pos: u64,
+    end: u64,
+    error: Option<Error>,
+}
+
+impl BigQueue {
+    /// Iterate over every record between head and tail without consuming
+    /// them. The head, persisted or in memory, does not move. A read-only
+    /// handle reloads the cursors first.
+    pub fn iter(&mut self) -> Iter<'_> {
+        let error = self.refresh().err();
+        Iter { pos: self.head_pos(), end: self.tail_pos(), queue: self, error }
+    }
+}
+
+impl<'a> Iter<'a> {
+    fn read_next(&mut self) -> Result<Vec<u8>> {
+        let (start, length) = self.queue.record_start(self.pos, self.end)?;
+        let mut data = vec![0u8; length as usize];
+        self.queue.copy_at(start, &mut data)?;
+        self.queue.check_crc_at(start, length, record_crc(&data))?;
+        self.pos = start + length + self.queue.trailer_len() as u64;
+        Ok(data)
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>>> {
+        if let Some(e) = self.error.take() {
+            self.pos = self.end;
+            return Some(Err(e));
+        }
+        if self.pos >= self.end {
+            return None;
+        }
+        let result = self.read_next();
+        if result.is_err() {
+            self.pos = self.end;
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{BigQueue, Config};
+
+    #[test]
+    fn test_iter() {
+        let dir = "/tmp/bigqueue_test_iter";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        for checksum in [false, true] {
+            let mut conf = Config::new();
+            conf.arena_size = 64;
+            conf.checksum = checksum;
+            let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+            let records: Vec<Vec<u8>> = (0..50u8).map(|i| vec![i; i as usize % 70]).collect();
+            for record in &records {
+                q.push(record).unwrap();
+            }
+            for _ in 0..7 {
+                q.dequeue().unwrap();
+            }
+
+            let seen: Vec<Vec<u8>> = q.iter().map(|r| r.unwrap()).collect();
+            assert_eq!(&seen[..], &records[7..]);
+            // nothing was consumed
+            assert_eq!(q.iter().count(), 43);
+            assert_eq!(q.pop().unwrap(), records[7]);
+        }
+    }
+}
@@ -9,8 +9,10 @@
 * A big, fast and persistent queue based on memory mapped file.
 *
 * ```rust
+* use std::fs;
 * use bigqueue::BigQueue;
 *
+* fs::create_dir_all("/tmp/bigqueue").expect("create dir error");
 * let mut q = BigQueue::new(&"/tmp/bigqueue", true).unwrap();
 *
 * let total = 10000;
@@ -36,59 +38,85 @@
 *
 * ```rust
 * use std::{fs, thread};
-* use std::time::Duration;
 *
-* fs::create_dir_all(PathBuf::from("/tmp/spsc")).expect("create dir error");
+* fs::create_dir_all("/tmp/spsc").expect("create dir error");
 * if let Ok((mut tx, mut rx)) = bigqueue::channel("/tmp/spsc", true){
 *     let v = b"1234567890abcdefghij";
-*     let total = 100000000;
+*     let total = 100000;
 *     let t = thread::spawn(move|| {
 *         for _i in 0..total {
 *             tx.enqueue(v).unwrap();
 *         }
 *     });
 *
-*     let two_sec = Duration::from_secs(2);
-*     thread::sleep(two_sec);
-*
-*     let start = PreciseTime::now();
 *     let mut count = 0;
-*     loop{
-*         if rx.dequeue().is_ok() {
-*             count = count + 1;
-*
-*         }
-*         if count == total {
-*             println!("count {}", count);
-*             break;
-*         }
-*
+*     while rx.recv().is_ok() {
+*         count = count + 1;
 *     }
-*     let end = PreciseTime::now();
-*     println!("{} seconds for enqueue and dequeue. {} ps", start.to(end), total*1000000/start.to(end).num_microseconds().unwrap());
+*     println!("count {}", count);
 *     t.join().unwrap();
 * }
 * ```
 */
 
+// failure_derive expands its impls inside an anonymous const
+#![allow(non_local_definitions)]
+
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
 
-use std::cell::UnsafeCell;
+use std::collections::BTreeMap;
 use std::io;
 use std::ops::Range;
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use lru::LruCache;
-use memmap::MmapMut;
 
 use crate::bigqueue::Index;
 
+pub use crate::bigqueue::FORMAT_VERSION;
+pub use crate::channel::{channel, channel_with_config, Receiver, Sender};
+pub use crate::delivery::Delivery;
+pub use crate::iter::Iter;
+pub use crate::migrate::{migrate, upgrade, Migration};
+pub use crate::process::{Consumer, Producer};
+pub use crate::record::RecordRef;
+pub use crate::recovery::Recovery;
+pub use crate::reserve::Reservation;
+pub use crate::stream::RecordReader;
+pub use crate::stats::Stats;
+pub use crate::subscription::Subscription;
+#[cfg(feature = "serde")]
+pub use crate::typed::{Codec, TypedBigQueue};
+#[cfg(feature = "bincode")]
+pub use crate::typed::Bincode;
+#[cfg(feature = "json")]
+pub use crate::typed::Json;
+#[cfg(feature = "msgpack")]
+pub use crate::typed::MessagePack;
+
 type Result<T> = std::result::Result<T, Error>;
 
+mod batch;
 mod bigqueue;
+mod channel;
+mod delivery;
+mod iter;
+mod lock;
+mod migrate;
+mod process;
+mod record;
+mod recovery;
+mod reserve;
+mod seek;
+mod stats;
+mod stream;
+mod subscription;
+#[cfg(feature = "serde")]
+mod typed;
 
 pub struct BigQueue {
     index: Index,
@@ -99,47 +127,29 @@ pub struct BigQueue {
     head_offset: usize,
     tail_aid: usize,
     tail_offset: usize,
-    q_head: Rc<UnsafeCell<bigqueue::Arena>>,
-    q_tail: Rc<UnsafeCell<bigqueue::Arena>>,
-    cache: LruCache<usize, Rc<UnsafeCell<bigqueue::Arena>>>,
-}
-
-pub fn channel(dir: &str, reset: bool) -> Result<(Sender, Receiver)> {
-    let a = Rc::new(UnsafeCell::new(BigQueue::new(dir, reset)?));
-    Ok((Sender::new(a.clone()), Receiver::new(a)))
-}
+    // records and payload bytes consumed up to the in-memory head
+    popped: u64,
+    popped_bytes: u64,
+    // records and payload bytes appended since the tail was last committed
+    appended: u64,
+    appended_bytes: u64,
+    marks: Vec<seek::Mark>,
+    q_head: bigqueue::Arena,
+    q_tail: bigqueue::Arena,
+    cache: LruCache<usize, bigqueue::Arena>,
 
+    // unacknowledged deliveries keyed by record position
+    deliveries: BTreeMap<u64, delivery::Pending>,
+    next_delivery: u64,
 
-pub struct Sender {
-    inner: Rc<UnsafeCell<BigQueue>>,
-}
-
-unsafe impl Send for Sender {}
-
-pub struct Receiver {
-    inner: Rc<UnsafeCell<BigQueue>>,
-}
-
-unsafe impl Send for Receiver {}
-
-impl Sender {
-    fn new(inner: Rc<UnsafeCell<BigQueue>>) -> Sender {
-        Sender { inner: inner }
-    }
-
-    pub fn enqueue(&mut self, elem: &[u8]) -> Result<()> {
-        unsafe { (*self.inner.get()).push(elem) }
-    }
-}
-
-impl Receiver {
-    fn new(inner: Rc<UnsafeCell<BigQueue>>) -> Receiver {
-        Receiver { inner }
-    }
+    // tail position covered by the last sync
+    synced_pos: u64,
+    unsynced: u64,
+    last_sync: Instant,
 
-    pub fn dequeue(&mut self) -> Result<()> {
-        unsafe { (*self.inner.get()).dequeue() }
-    }
+    recovery: Option<Recovery>,
+    // shared by every handle this one opened
+    lock: Arc<lock::DirLock>,
 }
 
 #[derive(Fail, Debug)]
@@ -160,6 +170,32 @@ pub enum Error {
     ReadLength,
     #[fail(display = "fail to read.")]
     Read,
+    #[fail(display = "corrupted record at arena {} offset {}.", aid, offset)]
+    Corrupted { aid: usize, offset: usize },
+    #[fail(display = "{} is not a valid subscription name.", _0)]
+    InvalidName(String),
+    #[fail(display = "delivery {} is not pending.", _0)]
+    UnknownDelivery(u64),
+    #[fail(display = "timed out waiting for the queue.")]
+    Timeout,
+    #[fail(display = "the other side of the channel is gone.")]
+    Disconnected,
+    #[fail(display = "a sender failed halfway through a write.")]
+    Poisoned,
+    #[fail(display = "{} is not a valid queue metadata file.", _0)]
+    InvalidFormat(String),
+    #[fail(display = "on-disk format version {} is not supported.", _0)]
+    UnsupportedVersion(u32),
+    #[fail(display = "{} is not empty.", _0)]
+    NotEmpty(String),
+    #[fail(display = "{} is locked by another process.", _0)]
+    Locked(String),
+    #[fail(display = "record {} is no longer retained.", _0)]
+    NotRetained(u64),
+    #[fail(display = "fail to encode or decode a record: {}.", _0)]
+    Codec(String),
+    #[fail(display = "queue is opened read-only.")]
+    ReadOnly,
     #[fail(display = "{}", _0)]
     Io(#[cause] io::Error),
 }
@@ -167,10 +203,47 @@ pub enum Error {
 
 const DEFAULT_ARENA_SIZE: usize = 128 * 1024 * 1024;
 const MIN_ARENAS_MAX_IN_MEM: u8 = 3;
+const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// When writes are flushed to disk. Without a flush, durability depends on
+/// the kernel writing back dirty pages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// Only flush on an explicit `BigQueue::sync`.
+    None,
+    /// Flush after every n records pushed or popped, batches included.
+    EveryN(u64),
+    /// Flush on the first push or pop once the interval has passed.
+    Interval(Duration),
+    /// Flush after every push and pop.
+    Always,
+}
 
+/// How `BigQueue::with_config` locks the queue directory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockMode {
+    /// One process owns the queue, further openers get `Error::Locked`.
+    Exclusive,
+    /// Any number of processes may inspect the queue while no one owns it.
+    /// Every method that would change it fails with `Error::ReadOnly`.
+    Shared,
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub arena_size: usize,
     pub max_arenas_in_mem: u8,
+    /// How long a record handed out by `receive` may stay unacknowledged
+    /// before it is delivered again.
+    pub visibility_timeout: Duration,
+    /// Store a CRC32C after every record and verify it on read.
+    pub checksum: bool,
+    pub durability: Durability,
+    /// Validate the records between head and tail when opening, and cut
+    /// the tail back to the last complete one. Only reliable with
+    /// `checksum`, see `BigQueue::recover`.
+    pub recover: bool,
+    pub lock: LockMode,
 }
 
 impl Config {
@@ -178,12 +251,23 @@ impl Config {
         Config {
             arena_size: DEFAULT_ARENA_SIZE,
             max_arenas_in_mem: MIN_ARENAS_MAX_IN_MEM,
+            visibility_timeout: DEFAULT_VISIBILITY_TIMEOUT,
+            checksum: false,
+            durability: Durability::None,
+            recover: false,
+            lock: LockMode::Exclusive,
         }
     }
 }
 
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
 #[inline]
-fn write_u64(mmap: &mut MmapMut, offset: usize, v: u64) -> Result<()> {
+fn write_u64(mmap: &mut [u8], offset: usize, v: u64) -> Result<()> {
     let r: Range<usize> = offset..offset + 8;
     if let Some(area) = mmap.get_mut(r) {
         area.copy_from_slice(&transform_u64_to_array_of_u8(v));
@@ -193,7 +277,7 @@ fn write_u64(mmap: &mut MmapMut, offset: usize, v: u64) -> Result<()> {
 }
 
 #[inline]
-fn write_bytes(mmap: &mut MmapMut, offset: usize, v: &[u8]) -> Result<()> {
+fn write_bytes(mmap: &mut [u8], offset: usize, v: &[u8]) -> Result<()> {
     let bytes_length = v.len();
     let r: Range<usize> = offset..offset + bytes_length;
     if let Some(area) = mmap.get_mut(r) {
@@ -204,7 +288,7 @@ fn write_bytes(mmap: &mut MmapMut, offset: usize, v: &[u8]) -> Result<()> {
 }
 
 #[inline]
-fn read_u64(mmap: &MmapMut, offset: usize) -> Option<u64> {
+fn read_u64(mmap: &[u8], offset: usize) -> Option<u64> {
     let r = Range { start: offset, end: offset + 8 };
     if let Some(slice) = mmap.get(r) {
         return Some(transform_array_of_u8_to_u64(slice));
@@ -212,15 +296,6 @@ fn read_u64(mmap: &MmapMut, offset: usize) -> Option<u64> {
     None
 }
 
-#[inline]
-fn read_bytes(mmap: &MmapMut, offset: usize, length: u64) -> Option<Vec<u8>> {
-    let r = Range { start: offset, end: offset + (length as usize) };
-    if let Some(slice) = mmap.get(r) {
-        return Some(slice.to_vec());
-    }
-    None
-}
-
 #[inline]
 fn transform_u64_to_array_of_u8(x: u64) -> [u8; 8] {
     use byteorder::{ByteOrder, LittleEndian};
@@ -233,4 +308,18 @@ fn transform_u64_to_array_of_u8(x: u64) -> [u8; 8] {
 fn transform_array_of_u8_to_u64(x: &[u8]) -> u64 {
     use byteorder::{ByteOrder, LittleEndian};
     LittleEndian::read_u64(x)
+}
+
+#[inline]
+fn transform_u32_to_array_of_u8(x: u32) -> [u8; 4] {
+    use byteorder::{ByteOrder, LittleEndian};
+    let mut bytes: [u8; 4] = [0; 4];
+    LittleEndian::write_u32(&mut bytes, x);
+    bytes
+}
+
+#[inline]
+fn transform_array_of_u8_to_u32(x: &[u8]) -> u32 {
+    use byteorder::{ByteOrder, LittleEndian};
+    LittleEndian::read_u32(x)
 }
\ No newline at end of file
@@ -0,0 +1,138 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use fs2::FileExt;
+
+use crate::{Error, LockMode, Result};
+
+const LOCK_FILE: &str = "queue.lock";
+
+/// Advisory `flock`s on the queue directory, released when dropped. A lock
+/// belongs to the open file, so handles in one process share a single
+/// `DirLock` instead of locking again.
+pub(crate) struct DirLock {
+    _files: Vec<File>,
+    read_only: bool,
+}
+
+impl DirLock {
+    pub(crate) fn acquire(dir: &Path, mode: LockMode) -> Result<DirLock> {
+        let exclusive = mode == LockMode::Exclusive;
+        Ok(DirLock {
+            _files: vec![lock_file(dir, LOCK_FILE, exclusive, exclusive)?],
+            read_only: !exclusive,
+        })
+    }
+
+    /// No lock at all, for read-only handles that must not get in the way
+    /// of the owner.
+    pub(crate) fn none() -> DirLock {
+        DirLock { _files: Vec::new(), read_only: true }
+    }
+
+    /// Lock the queue for one side of a cross-process queue: the queue lock
+    /// is shared with the other side, `role` is held exclusively so there is
+    /// only one producer and one consumer.
+    pub(crate) fn role(dir: &Path, role: &str) -> Result<DirLock> {
+        let queue = lock_file(dir, LOCK_FILE, false, true)?;
+        let role = lock_file(dir, &format!("{}.lock", role), true, true)?;
+        Ok(DirLock { _files: vec![queue, role], read_only: false })
+    }
+
+    /// Lock `name` exclusively, on top of the queue lock another `DirLock`
+    /// already holds.
+    pub(crate) fn exclusive(dir: &Path, name: &str) -> Result<DirLock> {
+        Ok(DirLock { _files: vec![lock_file(dir, name, true, true)?], read_only: false })
+    }
+
+    pub(crate) fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+fn lock_file(dir: &Path, name: &str, exclusive: bool, create: bool) -> Result<File> {
+    let path = dir.join(name);
+    let file = if create {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+    } else {
+        File::open(&path)
+    };
+    let file = match file {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::Exist(path.to_string_lossy().to_string()));
+        }
+        Err(e) => return Err(Error::Io(e)),
+    };
+
+    let locked = if exclusive {
+        FileExt::try_lock_exclusive(&file)
+    } else {
+        FileExt::try_lock_shared(&file)
+    };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+            Err(Error::Locked(dir.to_string_lossy().to_string()))
+        }
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{BigQueue, Config, Error, LockMode};
+
+    #[test]
+    fn test_lock() {
+        let dir = "/tmp/bigqueue_test_lock";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut q = BigQueue::new(dir, true).unwrap();
+        q.push(b"locked").unwrap();
+
+        let mut shared = Config::new();
+        shared.lock = LockMode::Shared;
+        for conf in [Config::new(), shared.clone()] {
+            match BigQueue::with_config(dir, false, conf) {
+                Err(Error::Locked(_)) => {}
+                Err(e) => panic!("unexpected {:?}", e),
+                Ok(_) => panic!("opened a locked queue"),
+            }
+        }
+        // handles opened from a queue share its lock
+        let sub = q.subscribe("inspector").unwrap();
+        drop(sub);
+        drop(q);
+
+        let mut a = BigQueue::with_config(dir, false, shared.clone()).unwrap();
+        let mut b = BigQueue::with_config(dir, false, shared).unwrap();
+        assert_eq!(a.peek().unwrap(), b"locked".to_vec());
+        assert_eq!(b.peek().unwrap(), b"locked".to_vec());
+        match a.pop() {
+            Err(Error::ReadOnly) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(b.push(b"nope").is_err());
+        assert!(BigQueue::new(dir, false).is_err());
+        drop(a);
+        drop(b);
+
+        let mut q = BigQueue::new(dir, false).unwrap();
+        assert_eq!(q.pop().unwrap(), b"locked".to_vec());
+    }
+}
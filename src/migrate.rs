@@ -0,0 +1,351 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::{BigQueue, Config, Error, LockMode, Result};
+use crate::bigqueue::header_pos;
+use crate::lock::DirLock;
+use crate::{transform_array_of_u8_to_u64, transform_u64_to_array_of_u8};
+
+// version 0 layout: index.dat holds head aid, head offset, tail aid and
+// tail offset as raw u64s, records carry no checksum
+const LEGACY_INDEX_FILE: &str = "index.dat";
+const LEGACY_INDEX_FILE_SIZE: u64 = 4 * 8;
+
+const META_FILE: &str = "meta.dat";
+const PROGRESS_FILE: &str = "migrate.progress";
+const SWAP_FILE: &str = "migrate.swap";
+const STAGING_DIR: &str = "upgrade";
+// records copied between two progress checkpoints
+const PROGRESS_INTERVAL: u64 = 1024;
+
+/// What a migration copied from the source queue.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Migration {
+    pub records: u64,
+    pub bytes: u64,
+}
+
+/// Copy the unconsumed records of the version 0 queue in `src`, written with
+/// arenas of `arena_size` bytes, into a new queue in `dst` opened with
+/// `conf`. `src` is left untouched.
+///
+/// Progress is checkpointed in `dst`, so calling it again after an
+/// interruption resumes where the last checkpoint left off.
+pub fn migrate(src: &str, dst: &str, arena_size: usize, conf: Config) -> Result<Migration> {
+    let report = copy(Path::new(src), Path::new(dst), arena_size, conf, None)?;
+    fs::remove_file(Path::new(dst).join(PROGRESS_FILE)).map_err(Error::Io)?;
+    Ok(report)
+}
+
+/// Upgrade the version 0 queue in `dir` in place. Records are copied into a
+/// staging directory inside `dir` that then replaces the old files. Like
+/// `migrate` it can be called again after an interruption, and it does
+/// nothing on a queue that is already current.
+pub fn upgrade(dir: &str, arena_size: usize, conf: Config) -> Result<Migration> {
+    let dir = Path::new(dir);
+    let _lock = DirLock::acquire(dir, LockMode::Exclusive)?;
+    let staging = dir.join(STAGING_DIR);
+    if !staging.exists() && dir.join(META_FILE).exists() {
+        return Ok(Migration::default());
+    }
+
+    let report = if staging.join(SWAP_FILE).exists() {
+        read_progress(&staging)?.ok_or(Error::Read)?.report
+    } else {
+        let report = copy(dir, &staging, arena_size, conf, None)?;
+        for path in queue_files(dir)? {
+            fs::remove_file(path).map_err(Error::Io)?;
+        }
+        File::create(staging.join(SWAP_FILE)).map_err(Error::Io)?;
+        report
+    };
+
+    // meta.dat goes last, until it is in place the queue does not open
+    let mut files = queue_files(&staging)?;
+    files.sort_by_key(|path| path.ends_with(META_FILE));
+    for path in files {
+        let name = path.file_name().ok_or(Error::Read)?;
+        fs::rename(&path, dir.join(name)).map_err(Error::Io)?;
+    }
+    fs::remove_dir_all(&staging).map_err(Error::Io)?;
+    Ok(report)
+}
+
+fn queue_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).map_err(Error::Io)?.flatten() {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "dat" || ext == "idx") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+struct Progress {
+    // position of the next record to copy in the source
+    src_pos: u64,
+    // destination tail once every record before `src_pos` is copied
+    dst_tail: u64,
+    done: bool,
+    report: Migration,
+}
+
+fn read_progress(dir: &Path) -> Result<Option<Progress>> {
+    let path = dir.join(PROGRESS_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = fs::read(&path).map_err(Error::Io)?;
+    if data.len() != 5 * 8 {
+        return Err(Error::InvalidFormat(path.to_string_lossy().to_string()));
+    }
+    let word = |i: usize| transform_array_of_u8_to_u64(&data[i * 8..i * 8 + 8]);
+    Ok(Some(Progress {
+        src_pos: word(0),
+        dst_tail: word(1),
+        done: word(4) != 0,
+        report: Migration { records: word(2), bytes: word(3) },
+    }))
+}
+
+/// Replace the progress file through a rename, so it is never torn.
+fn write_progress(dir: &Path, progress: &Progress) -> Result<()> {
+    let mut data = Vec::with_capacity(5 * 8);
+    for word in [progress.src_pos, progress.dst_tail, progress.report.records,
+        progress.report.bytes, progress.done as u64] {
+        data.extend_from_slice(&transform_u64_to_array_of_u8(word));
+    }
+    let tmp = dir.join(format!("{}.tmp", PROGRESS_FILE));
+    fs::write(&tmp, &data).map_err(Error::Io)?;
+    fs::rename(&tmp, dir.join(PROGRESS_FILE)).map_err(Error::Io)
+}
+
+/// Copy records from `src` into `dst`, stopping after `limit` records if
+/// given. Leaves a progress file in `dst`, marked done once the source is
+/// drained.
+fn copy(src: &Path, dst: &Path, arena_size: usize, conf: Config, limit: Option<u64>) -> Result<Migration> {
+    fs::create_dir_all(dst).map_err(Error::Io)?;
+    let progress = read_progress(dst)?;
+    if let Some(Progress { done: true, report, .. }) = progress {
+        // the source may already be partly deleted by `upgrade`
+        return Ok(report);
+    }
+
+    let mut reader = LegacyReader::open(src, arena_size)?;
+    let mut progress = match progress {
+        Some(progress) => progress,
+        None => {
+            if dst.join(META_FILE).exists() || dst.join(LEGACY_INDEX_FILE).exists() {
+                return Err(Error::NotEmpty(dst.to_string_lossy().to_string()));
+            }
+            // checkpoint before the queue exists, so a crash right after
+            // creating it is still recognised as our own
+            let progress = Progress { src_pos: reader.head, dst_tail: 0, done: false, report: Migration::default() };
+            write_progress(dst, &progress)?;
+            progress
+        }
+    };
+    let mut queue = BigQueue::with_config(&dst.to_string_lossy(), false, conf)?;
+    // drop whatever was copied after the last checkpoint
+    if queue.tail_pos() != progress.dst_tail {
+        queue.reset_tail(progress.dst_tail, progress.report.records, progress.report.bytes)?;
+    }
+
+    let mut copied = 0;
+    while progress.src_pos < reader.tail {
+        if limit == Some(copied) {
+            break;
+        }
+        let (data, next) = reader.read_record(progress.src_pos)?;
+        queue.push(&data)?;
+        progress.src_pos = next;
+        progress.dst_tail = queue.tail_pos();
+        progress.report.records += 1;
+        progress.report.bytes += data.len() as u64;
+        copied += 1;
+        if copied % PROGRESS_INTERVAL == 0 {
+            queue.sync()?;
+            write_progress(dst, &progress)?;
+        }
+    }
+
+    progress.done = progress.src_pos >= reader.tail;
+    queue.sync()?;
+    write_progress(dst, &progress)?;
+    Ok(progress.report)
+}
+
+/// Reads records of a version 0 queue straight from its files.
+struct LegacyReader {
+    dir: PathBuf,
+    arena_size: usize,
+    head: u64,
+    tail: u64,
+    file: Option<(usize, File)>,
+}
+
+impl LegacyReader {
+    fn open(dir: &Path, arena_size: usize) -> Result<LegacyReader> {
+        if dir.join(META_FILE).exists() {
+            return Err(Error::UnsupportedVersion(crate::FORMAT_VERSION));
+        }
+        let path = dir.join(LEGACY_INDEX_FILE);
+        let index = fs::read(&path).map_err(Error::Io)?;
+        if index.len() as u64 != LEGACY_INDEX_FILE_SIZE {
+            return Err(Error::InvalidFormat(path.to_string_lossy().to_string()));
+        }
+        let word = |i: usize| transform_array_of_u8_to_u64(&index[i * 8..i * 8 + 8]);
+        let size = arena_size as u64;
+        Ok(LegacyReader {
+            dir: dir.to_path_buf(),
+            arena_size,
+            head: word(0) * size + word(1),
+            tail: word(2) * size + word(3),
+            file: None,
+        })
+    }
+
+    /// Read the record at `pos`, returning it with the position of the next.
+    fn read_record(&mut self, pos: u64) -> Result<(Vec<u8>, u64)> {
+        let size = self.arena_size as u64;
+        let mut pos = header_pos(pos, size);
+        let start = pos;
+        let mut length = [0u8; 8];
+        self.read_exact_at(pos, &mut length)?;
+        pos += 8;
+
+        let length = transform_array_of_u8_to_u64(&length);
+        if length > self.tail.saturating_sub(pos) {
+            return Err(Error::Corrupted {
+                aid: (start / size) as usize,
+                offset: (start % size) as usize,
+            });
+        }
+        let mut data = vec![0u8; length as usize];
+        self.read_exact_at(pos, &mut data)?;
+        Ok((data, pos + length))
+    }
+
+    fn read_exact_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        let size = self.arena_size as u64;
+        let mut pos = pos;
+        let mut done = 0;
+        while done < buf.len() {
+            let aid = (pos / size) as usize;
+            let offset = pos % size;
+            if self.file.as_ref().map(|(id, _)| *id) != Some(aid) {
+                let path = self.dir.join(format!("arena_{}.dat", aid));
+                self.file = Some((aid, File::open(path).map_err(Error::Io)?));
+            }
+            let (_, file) = self.file.as_mut().ok_or(Error::Read)?;
+            let n = (buf.len() - done).min((size - offset) as usize);
+            file.seek(SeekFrom::Start(offset)).map_err(Error::Io)?;
+            file.read_exact(&mut buf[done..done + n]).map_err(Error::Io)?;
+            done += n;
+            pos += n as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::{BigQueue, Config};
+
+    /// Lay out records the way version 0 wrote them.
+    fn write_legacy(dir: &str, arena_size: usize, records: &[Vec<u8>], head: usize) {
+        fs::create_dir_all(dir).expect("failed to create dir");
+        for entry in fs::read_dir(dir).unwrap().flatten() {
+            if entry.path().is_dir() {
+                fs::remove_dir_all(entry.path()).unwrap();
+            } else {
+                fs::remove_file(entry.path()).unwrap();
+            }
+        }
+        let mut bytes = Vec::new();
+        let mut head_pos = 0;
+        for (i, record) in records.iter().enumerate() {
+            if bytes.len() % arena_size + 8 > arena_size {
+                bytes.resize((bytes.len() / arena_size + 1) * arena_size, 0);
+            }
+            if i == head {
+                head_pos = bytes.len();
+            }
+            bytes.extend_from_slice(&(record.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(record);
+        }
+        let tail_pos = bytes.len();
+        bytes.resize((tail_pos / arena_size + 1) * arena_size, 0);
+        for (aid, arena) in bytes.chunks(arena_size).enumerate() {
+            fs::write(Path::new(dir).join(format!("arena_{}.dat", aid)), arena).unwrap();
+        }
+        let mut index = Vec::new();
+        for word in [head_pos / arena_size, head_pos % arena_size,
+            tail_pos / arena_size, tail_pos % arena_size] {
+            index.extend_from_slice(&(word as u64).to_le_bytes());
+        }
+        fs::write(Path::new(dir).join("index.dat"), index).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_resumes() {
+        let src = "/tmp/bigqueue_test_migrate_src";
+        let dst = "/tmp/bigqueue_test_migrate_dst";
+        let records: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i; 3 + i as usize % 11]).collect();
+        write_legacy(src, 50, &records, 5);
+        let _ = fs::remove_dir_all(dst);
+
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        conf.checksum = true;
+        let partial = super::copy(Path::new(src), Path::new(dst), 50, conf.clone(), Some(10)).unwrap();
+        assert_eq!(partial.records, 10);
+
+        // records copied after the last checkpoint are dropped on resume
+        let mut q = BigQueue::new(dst, false).unwrap();
+        q.push(b"copied twice").unwrap();
+        drop(q);
+
+        let report = super::migrate(src, dst, 50, conf).unwrap();
+        assert_eq!(report.records, 35);
+        assert!(!Path::new(dst).join(super::PROGRESS_FILE).exists());
+        let mut q = BigQueue::new(dst, false).unwrap();
+        assert!(q.config().checksum);
+        for record in &records[5..] {
+            assert_eq!(&q.pop().unwrap(), record);
+        }
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn test_upgrade_in_place() {
+        let dir = "/tmp/bigqueue_test_upgrade";
+        let records: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 30]).collect();
+        write_legacy(dir, 64, &records, 0);
+        assert!(BigQueue::new(dir, false).is_err());
+
+        let mut conf = Config::new();
+        conf.arena_size = 128;
+        assert_eq!(super::upgrade(dir, 64, conf.clone()).unwrap().records, 20);
+        assert_eq!(super::upgrade(dir, 64, conf).unwrap().records, 0);
+        let mut q = BigQueue::new(dir, false).unwrap();
+        assert_eq!(q.config().arena_size, 128);
+        for record in &records {
+            assert_eq!(&q.pop().unwrap(), record);
+        }
+        assert!(q.is_empty());
+    }
+}
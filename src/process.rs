@@ -0,0 +1,221 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{BigQueue, Config, Result};
+use crate::bigqueue::check_dir;
+use crate::channel::TailFollower;
+use crate::lock::DirLock;
+
+/// The writing side of a queue shared between processes. Records are
+/// published through the tail word in `index.dat`, which every process maps.
+pub struct Producer {
+    queue: BigQueue,
+}
+
+/// The reading side of a queue shared between processes, see `Producer`.
+pub struct Consumer {
+    queue: BigQueue,
+}
+
+impl Producer {
+    /// Open the queue in `dir` as its only producer. Fails with
+    /// `Error::Locked` if another producer or a `BigQueue` has it open.
+    pub fn open(dir: &str, conf: Config) -> Result<Producer> {
+        check_dir(dir)?;
+        let lock = DirLock::role(Path::new(dir), "tail")?;
+        let mut queue = BigQueue::with_lock(dir, false, conf, lock)?;
+        // republish, the tail may have been moved by a handle that did not
+        queue.commit_tail()?;
+        Ok(Producer { queue })
+    }
+
+    /// Append a record and return its sequence number, see `BigQueue::push`.
+    pub fn enqueue(&mut self, elem: &[u8]) -> Result<u64> {
+        self.queue.push(elem)
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.queue.sync()
+    }
+}
+
+impl Consumer {
+    /// Open the queue in `dir` as its only consumer. The producer may open
+    /// it before or after.
+    pub fn open(dir: &str, conf: Config) -> Result<Consumer> {
+        check_dir(dir)?;
+        let lock = DirLock::role(Path::new(dir), "head")?;
+        let mut conf = conf;
+        // the tail belongs to the producer
+        conf.recover = false;
+        let queue = BigQueue::with_lock(dir, false, conf, lock)?;
+        Ok(Consumer { queue })
+    }
+
+    /// Sequence number of the record the next receive returns.
+    pub fn next_seq(&self) -> u64 {
+        self.queue.next_seq()
+    }
+
+    pub fn dequeue(&mut self) -> Result<()> {
+        self.queue.refresh_tail();
+        self.queue.dequeue()
+    }
+
+    /// Pop the next record without blocking, `Error::QueueEmpty` if there
+    /// is none yet.
+    pub fn try_recv(&mut self) -> Result<Vec<u8>> {
+        self.try_recv_with(|data| data.to_vec())
+    }
+
+    /// Pop the next record, sleeping until the producer publishes one. There
+    /// is no disconnect: a consumer whose producer is gone waits forever.
+    pub fn recv(&mut self) -> Result<Vec<u8>> {
+        self.recv_deadline_with(None, |data| data.to_vec())
+    }
+
+    /// Like `recv`, but gives up with `Error::Timeout` after `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        self.recv_deadline_with(Some(Instant::now() + timeout), |data| data.to_vec())
+    }
+
+    /// Zero-copy `try_recv`.
+    pub fn try_recv_with<F, R>(&mut self, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> R {
+        self.try_recv_followed(f)
+    }
+
+    /// Zero-copy `recv`.
+    pub fn recv_with<F, R>(&mut self, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> R {
+        self.recv_deadline_with(None, f)
+    }
+
+    /// Zero-copy `recv_timeout`.
+    pub fn recv_timeout_with<F, R>(&mut self, timeout: Duration, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> R {
+        self.recv_deadline_with(Some(Instant::now() + timeout), f)
+    }
+}
+
+impl TailFollower for Consumer {
+    fn queue(&mut self) -> &mut BigQueue {
+        &mut self.queue
+    }
+
+    fn follow_tail(&mut self) {
+        self.queue.refresh_tail();
+    }
+
+    /// Sleep until the published tail moves past `seen` or `deadline`
+    /// passes. Producers only wake the counter when someone is registered
+    /// as waiting, so register first and check the tail after.
+    fn wait(&self, seen: u64, deadline: Option<Instant>) -> bool {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return false;
+                }
+                Some(deadline - now)
+            }
+            None => None,
+        };
+        let index = &self.queue.index;
+        let waiters = index.notify_waiters();
+        waiters.fetch_add(1, Ordering::SeqCst);
+        let seq = index.notify_seq().load(Ordering::SeqCst);
+        if index.published_tail().unwrap_or(seen) == seen {
+            wait_on(index.notify_seq(), seq, timeout);
+        }
+        waiters.fetch_sub(1, Ordering::SeqCst);
+        true
+    }
+}
+
+/// Sleep while `word` still holds `expected`, at most `timeout`. Returns
+/// early on spurious wakeups, callers check their condition again.
+#[cfg(target_os = "linux")]
+fn wait_on(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let ts = timeout.map(|t| libc::timespec {
+        tv_sec: t.as_secs() as libc::time_t,
+        tv_nsec: t.subsec_nanos() as libc::c_long,
+    });
+    let ts_ptr = ts.as_ref().map_or(std::ptr::null(), |t| t as *const libc::timespec);
+    // not FUTEX_PRIVATE_FLAG, the word lives in a mapping shared between processes
+    unsafe {
+        libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAIT, expected, ts_ptr);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wait_on(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    const POLL_INTERVAL: Duration = Duration::from_millis(1);
+    if word.load(Ordering::SeqCst) == expected {
+        std::thread::sleep(timeout.map_or(POLL_INTERVAL, |t| t.min(POLL_INTERVAL)));
+    }
+}
+
+/// Wake every process sleeping in `wait_on` for `word`.
+#[cfg(target_os = "linux")]
+pub(crate) fn wake_all(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAKE, i32::MAX);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn wake_all(_word: &AtomicU32) {}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+    use std::time::Duration;
+
+    use crate::{BigQueue, Config, Error};
+    use super::{Consumer, Producer};
+
+    #[test]
+    fn test_producer_consumer() {
+        let dir = "/tmp/bigqueue_test_process";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        drop(BigQueue::with_config(dir, true, conf.clone()).unwrap());
+
+        let mut rx = Consumer::open(dir, conf.clone()).unwrap();
+        match rx.recv_timeout(Duration::from_millis(10)) {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        let mut tx = Producer::open(dir, conf.clone()).unwrap();
+        for opened in [Producer::open(dir, conf.clone()).err(), BigQueue::new(dir, false).err()] {
+            match opened {
+                Some(Error::Locked(_)) => {}
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+
+        let t = thread::spawn(move || {
+            for i in 0..2000u32 {
+                tx.enqueue(&i.to_le_bytes()).unwrap();
+                if i % 500 == 0 {
+                    thread::sleep(Duration::from_millis(5));
+                }
+            }
+        });
+        for i in 0..2000u32 {
+            assert_eq!(rx.recv().unwrap(), i.to_le_bytes().to_vec());
+        }
+        t.join().unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+}
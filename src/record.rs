@@ -0,0 +1,149 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::ops::{Deref, Range};
+
+use crate::{BigQueue, Error, Result};
+use crate::bigqueue::record_crc;
+
+enum Payload {
+    // inside one mapped arena
+    Mapped(usize, Range<usize>),
+    // straddles two arenas, copied once
+    Copied(Vec<u8>),
+}
+
+/// The head record, borrowed straight from the arena mapping. Returned by
+/// `BigQueue::peek_ref` and `BigQueue::pop_ref`; for the latter the record
+/// is popped when the guard is committed or dropped.
+pub struct RecordRef<'a> {
+    queue: &'a mut BigQueue,
+    payload: Payload,
+    next: u64,
+    pop: bool,
+}
+
+impl BigQueue {
+    /// Like `peek`, without copying the payload unless it straddles two
+    /// arenas.
+    pub fn peek_ref(&mut self) -> Result<RecordRef<'_>> {
+        self.refresh()?;
+        RecordRef::new(self, false)
+    }
+
+    /// Like `pop`, without copying the payload unless it straddles two
+    /// arenas. The head moves once the guard is committed or dropped.
+    pub fn pop_ref(&mut self) -> Result<RecordRef<'_>> {
+        self.check_writable()?;
+        RecordRef::new(self, true)
+    }
+}
+
+impl<'a> RecordRef<'a> {
+    fn new(queue: &'a mut BigQueue, pop: bool) -> Result<RecordRef<'a>> {
+        if queue.is_empty() {
+            return Err(Error::QueueEmpty);
+        }
+        let (start, length) = queue.record_start(queue.head_pos(), queue.tail_pos())?;
+        let next = start + length + queue.trailer_len() as u64;
+
+        let (start_aid, start_offset) = queue.split_pos(start);
+        let payload = if start_offset + length as usize <= queue.config.arena_size {
+            queue.cache_arena(start_aid)?;
+            Payload::Mapped(start_aid, start_offset..start_offset + length as usize)
+        } else {
+            let mut data = vec![0u8; length as usize];
+            queue.copy_at(start, &mut data)?;
+            Payload::Copied(data)
+        };
+
+        let record = RecordRef { queue, payload, next, pop };
+        if record.queue.config.checksum {
+            let crc = record_crc(&record);
+            record.queue.check_crc_at(start, length, crc)?;
+        }
+        Ok(record)
+    }
+
+    /// Sequence number of the record, see `BigQueue::push`.
+    pub fn seq(&self) -> u64 {
+        self.queue.next_seq()
+    }
+
+    /// Pop the record now, reporting a failure to persist the head. Does
+    /// nothing for a guard from `peek_ref`.
+    pub fn commit(mut self) -> Result<()> {
+        self.commit_inner()
+    }
+
+    fn commit_inner(&mut self) -> Result<()> {
+        if !self.pop {
+            return Ok(());
+        }
+        self.pop = false;
+        let length = self.len();
+        self.queue.advance_head(self.next, length)
+    }
+}
+
+impl<'a> Deref for RecordRef<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.payload {
+            Payload::Mapped(aid, range) => {
+                let arena = self.queue.arena_bytes(*aid).expect("arena of a borrowed record is not mapped");
+                &arena[range.clone()]
+            }
+            Payload::Copied(data) => data,
+        }
+    }
+}
+
+impl<'a> Drop for RecordRef<'a> {
+    fn drop(&mut self) {
+        let _ = self.commit_inner();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{BigQueue, Config, Error};
+
+    #[test]
+    fn test_peek_and_pop_ref() {
+        let dir = "/tmp/bigqueue_test_record_ref";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        conf.checksum = true;
+        let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+        let records: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i; 5 + i as usize]).collect();
+        for record in &records {
+            q.push(record).unwrap();
+        }
+
+        assert_eq!(&*q.peek_ref().unwrap(), &records[0][..]);
+        assert_eq!(&*q.peek_ref().unwrap(), &records[0][..]);
+        for record in &records[..20] {
+            let r = q.pop_ref().unwrap();
+            assert_eq!(&*r, &record[..]);
+        }
+        for record in &records[20..] {
+            let r = q.pop_ref().unwrap();
+            assert_eq!(&*r, &record[..]);
+            r.commit().unwrap();
+        }
+        assert!(q.is_empty());
+        match q.pop_ref().err() {
+            Some(Error::QueueEmpty) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
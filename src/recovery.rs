@@ -0,0 +1,143 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::{BigQueue, Result};
+
+/// What `BigQueue::recover` found between the head and the tail.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recovery {
+    /// Complete records left in the queue.
+    pub records: u64,
+    /// Tail (arena, offset) before the scan.
+    pub tail: (usize, usize),
+    /// Tail (arena, offset) after the scan, equal to `tail` if nothing was cut.
+    pub truncated_to: (usize, usize),
+    /// Bytes cut off the end of the queue.
+    pub discarded_bytes: u64,
+}
+
+impl BigQueue {
+    /// Walk every record between head and tail and move the tail back to the
+    /// end of the last complete one. A record is incomplete when its length
+    /// runs past the tail, its arena is missing or, with checksums on, its
+    /// CRC does not match.
+    ///
+    /// Without checksums a torn tail is only caught if a length runs past
+    /// it. Zeroed space the tail was persisted over reads as records of
+    /// zero bytes and stays in the queue.
+    ///
+    /// Runs on open when `Config::recover` is set.
+    pub fn recover(&mut self) -> Result<Recovery> {
+        self.check_writable()?;
+        let tail = self.tail_pos();
+        let mut pos = self.head_pos();
+        let mut records = 0;
+        let mut bytes = 0;
+        while pos < tail {
+            let (aid, _) = self.split_pos(pos);
+            if !self.dir.join(format!("arena_{}.dat", aid)).exists() {
+                break;
+            }
+            match self.check_at(pos) {
+                Ok((end, length)) => {
+                    pos = end;
+                    records += 1;
+                    bytes += length as u64;
+                }
+                Err(_) => break,
+            }
+        }
+
+        let report = Recovery {
+            records,
+            tail: self.split_pos(tail),
+            truncated_to: self.split_pos(pos.min(tail)),
+            discarded_bytes: tail.saturating_sub(pos),
+        };
+        if pos < tail {
+            self.synced_pos = self.synced_pos.min(pos);
+            self.reset_tail(pos, self.popped + records, self.popped_bytes + bytes)?;
+        }
+        self.recovery = Some(report.clone());
+        Ok(report)
+    }
+
+    /// Report of the last recovery scan, if one ran.
+    pub fn recovery(&self) -> Option<&Recovery> {
+        self.recovery.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{BigQueue, Config};
+
+    #[test]
+    fn test_recover_truncated_tail() {
+        let dir = "/tmp/bigqueue_test_recovery";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        conf.checksum = true;
+        let mut q = BigQueue::with_config(dir, true, conf.clone()).unwrap();
+        for i in 0..10u8 {
+            q.push(&[i; 20]).unwrap();
+        }
+        let tail = q.tail_pos();
+
+        // a tail persisted ahead of its data, as after a crash
+        q.seek_tail(tail + 16).unwrap();
+        q.commit_tail().unwrap();
+        drop(q);
+
+        conf.recover = true;
+        let mut q = BigQueue::with_config(dir, false, conf).unwrap();
+        let report = q.recovery().unwrap().clone();
+        assert_eq!(report.records, 10);
+        assert_eq!(report.discarded_bytes, 16);
+        assert_eq!(q.tail_pos(), tail);
+
+        for i in 0..10u8 {
+            assert_eq!(q.pop().unwrap(), vec![i; 20]);
+        }
+        assert!(q.is_empty());
+        q.push(b"after").unwrap();
+        assert_eq!(q.pop().unwrap(), b"after".to_vec());
+        assert_eq!(q.recover().unwrap().discarded_bytes, 0);
+    }
+
+    #[test]
+    fn test_recover_without_checksum() {
+        let dir = "/tmp/bigqueue_test_recovery_no_checksum";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        let mut q = BigQueue::with_config(dir, true, conf.clone()).unwrap();
+        for i in 0..10u8 {
+            q.push(&[i; 24]).unwrap();
+        }
+        let tail = q.tail_pos();
+        q.seek_tail(tail + 16).unwrap();
+        q.commit_tail().unwrap();
+        drop(q);
+
+        // the zeroed 16 bytes pass as two empty records
+        conf.recover = true;
+        let mut q = BigQueue::with_config(dir, false, conf).unwrap();
+        let report = q.recovery().unwrap().clone();
+        assert_eq!(report.records, 12);
+        assert_eq!(report.discarded_bytes, 0);
+        for i in 0..10u8 {
+            assert_eq!(q.pop().unwrap(), vec![i; 24]);
+        }
+        assert_eq!(q.pop().unwrap(), Vec::<u8>::new());
+        assert_eq!(q.pop().unwrap(), Vec::<u8>::new());
+        assert!(q.is_empty());
+    }
+}
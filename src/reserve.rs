@@ -0,0 +1,180 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::ops::{Deref, DerefMut};
+
+use crate::{BigQueue, Result};
+use crate::bigqueue::record_crc;
+
+enum Region {
+    // payload offset inside the tail arena
+    Mapped(usize),
+    // crosses the end of the tail arena, copied in on commit
+    Buffered(Vec<u8>),
+}
+
+/// Room for one record at the tail, written in place. Returned by
+/// `BigQueue::reserve`. The record is pushed on `commit`; dropping the
+/// guard without committing discards it.
+pub struct Reservation<'a> {
+    queue: &'a mut BigQueue,
+    region: Region,
+    length: usize,
+    // tail and seek index marks to go back to on abort
+    start: u64,
+    marks: usize,
+    done: bool,
+}
+
+impl BigQueue {
+    /// Reserve a record of exactly `length` bytes at the tail and hand out
+    /// its payload to be filled in place. The payload starts zeroed only if
+    /// the arena space was never used.
+    ///
+    /// A payload that crosses the end of the tail arena, as every payload
+    /// larger than an arena does, is filled in a heap buffer instead and
+    /// copied in on commit. `push_stream` avoids holding such records in
+    /// memory.
+    pub fn reserve(&mut self, length: usize) -> Result<Reservation<'_>> {
+        self.check_writable()?;
+        let start = self.tail_pos();
+        let marks = self.marks.len();
+        self.mark(start);
+        let offset = self.write_length(self.tail_offset, length as u64);
+        self.tail_offset = offset;
+
+        let region = if offset + length <= self.config.arena_size {
+            Region::Mapped(offset)
+        } else {
+            Region::Buffered(vec![0u8; length])
+        };
+        Ok(Reservation { queue: self, region, length, start, marks, done: false })
+    }
+}
+
+impl<'a> Reservation<'a> {
+    /// Push the record and return its sequence number, see
+    /// `BigQueue::push`.
+    pub fn commit(mut self) -> Result<u64> {
+        self.done = true;
+        let queue = &mut *self.queue;
+        let crc = match &self.region {
+            Region::Mapped(offset) => {
+                let end = offset + self.length;
+                let crc = record_crc(&queue.q_tail.bytes()[*offset..end]);
+                if end == queue.config.arena_size {
+                    queue.flip_tail_page_forward();
+                    queue.tail_offset = 0;
+                } else {
+                    queue.tail_offset = end;
+                }
+                crc
+            }
+            Region::Buffered(data) => {
+                queue.write_bytes(queue.tail_offset, data)?;
+                record_crc(data)
+            }
+        };
+        queue.end_record(self.length, crc)?;
+        queue.commit_tail().map(|pushed| pushed - 1)
+    }
+
+    fn abort(&mut self) -> Result<()> {
+        self.done = true;
+        self.queue.marks.truncate(self.marks);
+        self.queue.seek_tail(self.start)
+    }
+}
+
+impl<'a> Deref for Reservation<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.region {
+            Region::Mapped(offset) => &self.queue.q_tail.bytes()[*offset..*offset + self.length],
+            Region::Buffered(data) => data,
+        }
+    }
+}
+
+impl<'a> DerefMut for Reservation<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match &mut self.region {
+            Region::Mapped(offset) => {
+                let arena = self.queue.q_tail.bytes_mut().expect("reserved in a read-only arena");
+                &mut arena[*offset..*offset + self.length]
+            }
+            Region::Buffered(data) => data,
+        }
+    }
+}
+
+impl<'a> Drop for Reservation<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{BigQueue, Config};
+
+    #[test]
+    fn test_reserve() {
+        let dir = "/tmp/bigqueue_test_reserve";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        conf.checksum = true;
+        let mut q = BigQueue::with_config(dir, true, conf.clone()).unwrap();
+
+        for i in 0..20u8 {
+            let mut r = q.reserve(3 * i as usize).unwrap();
+            r.iter_mut().for_each(|b| *b = i);
+            if i % 3 == 0 {
+                drop(r);
+            } else {
+                r.commit().unwrap();
+            }
+        }
+        // an aborted reservation leaves nothing behind
+        let mut r = q.reserve(100).unwrap();
+        r[0] = 1;
+        drop(r);
+        drop(q);
+
+        let mut q = BigQueue::with_config(dir, false, conf).unwrap();
+        for i in (0..20u8).filter(|i| i % 3 != 0) {
+            assert_eq!(q.pop().unwrap(), vec![i; 3 * i as usize]);
+        }
+        assert!(q.is_empty());
+        assert_eq!(q.next_seq(), 13);
+    }
+
+    #[test]
+    fn test_reserve_larger_than_arena() {
+        let dir = "/tmp/bigqueue_test_reserve_large";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        conf.checksum = true;
+        let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+
+        // spans four arenas, buffered and copied in on commit
+        let record: Vec<u8> = (0..200u8).collect();
+        let mut r = q.reserve(record.len()).unwrap();
+        r.copy_from_slice(&record);
+        assert_eq!(r.commit().unwrap(), 0);
+        q.push(b"next").unwrap();
+        assert_eq!(q.pop().unwrap(), record);
+        assert_eq!(q.pop().unwrap(), b"next".to_vec());
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{BigQueue, Error, Result};
+use crate::{transform_array_of_u8_to_u64, transform_u64_to_array_of_u8};
+use crate::bigqueue::{arena_id, header_pos};
+
+// Next to every arena, `arena_<id>.idx` lists the first record starting in
+// the arena and in every SEEK_INDEX_STEP bytes of it after that:
+//
+// entry: seq u64 | bytes pushed before the record u64 | position u64 | push time in ms u64
+//
+// Queues written before the index existed have no entries for their older
+// records. Those are indexed on the first seek by walking them from the
+// start of the oldest head's arena, with a push time of zero.
+const SEEK_INDEX_STEP: u64 = 64 * 1024;
+const SEEK_ENTRY_SIZE: usize = 32;
+
+/// Index entry for a record appended but not committed yet. Its sequence
+/// number is only known once the commit counts it.
+pub(crate) struct Mark {
+    // records and bytes appended before it since the last commit
+    appended: u64,
+    appended_bytes: u64,
+    pos: u64,
+    time: u64,
+}
+
+struct Entry {
+    seq: u64,
+    bytes: u64,
+    pos: u64,
+    time: u64,
+}
+
+impl BigQueue {
+    /// Move the head to the record with sequence number `seq`, backwards or
+    /// forwards, as long as its arena has not been removed by `shrink`.
+    /// Pending deliveries are dropped.
+    pub fn seek(&mut self, seq: u64) -> Result<()> {
+        self.check_writable()?;
+        let (pushed, pushed_bytes) = self.index.get_tail_counts();
+        let entry = if seq == pushed {
+            Entry { seq, bytes: pushed_bytes, pos: self.tail_pos(), time: 0 }
+        } else {
+            self.seek_entries()?.into_iter()
+                .take_while(|e| e.seq <= seq)
+                .last()
+                .filter(|_| seq < pushed)
+                .ok_or(Error::NotRetained(seq))?
+        };
+
+        let (mut pos, mut count, mut bytes) = (entry.pos, entry.seq, entry.bytes);
+        while count < seq {
+            let (end, length) = self.check_at(pos)?;
+            pos = end;
+            count += 1;
+            bytes += length as u64;
+        }
+
+        let (aid, offset) = self.split_pos(pos);
+        self.seek_head(aid, offset)?;
+        self.deliveries.clear();
+        self.write_pending()?;
+        self.popped = count;
+        self.popped_bytes = bytes;
+        self.persist_head(0)
+    }
+
+    /// Move the head back or forth to the records pushed from `time` on and
+    /// return the sequence number it now points at. Push times are only
+    /// indexed every so often, so up to one index step of records pushed
+    /// before `time` may be replayed too. Before the oldest indexed record,
+    /// the head goes to that record.
+    pub fn seek_to_time(&mut self, time: SystemTime) -> Result<u64> {
+        self.check_writable()?;
+        let time = millis(time);
+        // the last record indexed before `time`, nothing before it is newer
+        let entries = self.seek_entries()?;
+        let seq = match entries.iter().take_while(|e| e.time < time).last().or(entries.first()) {
+            Some(entry) => entry.seq,
+            None => self.next_seq(),
+        };
+        self.seek(seq)?;
+        Ok(seq)
+    }
+
+    /// Remember the record about to be appended at `pos` for the seek index
+    /// if it is the first one in its arena or index step.
+    pub(crate) fn mark(&mut self, pos: u64) {
+        if let Some(start) = step_start(pos, self.config.arena_size as u64) {
+            self.marks.push(Mark {
+                appended: self.appended,
+                appended_bytes: self.appended_bytes,
+                pos: start,
+                time: millis(SystemTime::now()),
+            });
+        }
+    }
+
+    /// Write the marks of records committed after `pushed` records of
+    /// `pushed_bytes` bytes. The index is a hint, a failed write only
+    /// makes the records behind it slower to reach.
+    pub(crate) fn write_marks(&self, marks: Vec<Mark>, pushed: u64, pushed_bytes: u64) {
+        for mark in marks {
+            let entry = Entry {
+                seq: pushed + mark.appended,
+                bytes: pushed_bytes + mark.appended_bytes,
+                pos: mark.pos,
+                time: mark.time,
+            };
+            let (aid, _) = self.split_pos(mark.pos);
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(seek_index_path(&self.dir, aid))
+                .and_then(|mut file| file.write_all(&entry.to_bytes()));
+        }
+    }
+
+    /// Index entries of every retained record's arena and index step, in
+    /// sequence order.
+    fn seek_entries(&mut self) -> Result<Vec<Entry>> {
+        let mut entries = self.read_seek_entries()?;
+        let first = entries.first().map_or(self.tail_pos(), |e| e.pos);
+        match self.oldest_head() {
+            Some((pos, seq, bytes)) if pos < first => {
+                let mut rebuilt = self.rebuild_seek_entries(pos, seq, bytes, first);
+                rebuilt.append(&mut entries);
+                Ok(rebuilt)
+            }
+            _ => Ok(entries),
+        }
+    }
+
+    /// Index the records around the head at `pos` up to `end`, the head
+    /// being record `seq` with `bytes` bytes pushed before it. Entries of arenas
+    /// without an index file are written to a new one, the others are only
+    /// kept for this seek.
+    fn rebuild_seek_entries(&mut self, mut pos: u64, mut seq: u64, mut bytes: u64, end: u64) -> Vec<Entry> {
+        let arena_size = self.config.arena_size as u64;
+        pos = header_pos(pos, arena_size);
+
+        // records before the head are retained as long as their arena is.
+        // Go back an arena at a time while walking from its start meets the
+        // first record found so far, which fails when a record spills into
+        // the arena from the one before it.
+        while pos > 0 {
+            let from = (pos - 1) / arena_size * arena_size;
+            let (mut walked, mut n, mut n_bytes) = (from, 0, 0);
+            while header_pos(walked, arena_size) < pos {
+                match self.check_within(walked, pos) {
+                    Ok((next, length)) => {
+                        walked = next;
+                        n += 1;
+                        n_bytes += length as u64;
+                    }
+                    Err(_) => break,
+                }
+            }
+            if header_pos(walked, arena_size) != pos || n > seq || n_bytes > bytes {
+                break;
+            }
+            pos = from;
+            seq -= n;
+            bytes -= n_bytes;
+        }
+
+        let mut entries: Vec<Entry> = Vec::new();
+        while pos < end {
+            if entries.is_empty() || step_start(pos, arena_size).is_some() {
+                entries.push(Entry { seq, bytes, pos: header_pos(pos, arena_size), time: 0 });
+            }
+            match self.check_at(pos) {
+                Ok((next, length)) => {
+                    pos = next;
+                    seq += 1;
+                    bytes += length as u64;
+                }
+                Err(_) => break,
+            }
+        }
+
+        let mut files: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+        for entry in &entries {
+            let (aid, _) = self.split_pos(entry.pos);
+            files.entry(aid).or_default().extend_from_slice(&entry.to_bytes());
+        }
+        for (aid, data) in files {
+            let _ = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(seek_index_path(&self.dir, aid))
+                .and_then(|mut file| file.write_all(&data));
+        }
+        entries
+    }
+
+    /// Entries read from the index files of every arena still on disk.
+    fn read_seek_entries(&self) -> Result<Vec<Entry>> {
+        let tail = self.tail_pos();
+        let (pushed, _) = self.index.get_tail_counts();
+        let mut aids = Vec::new();
+        for entry in fs::read_dir(&self.dir).map_err(Error::Io)?.flatten() {
+            if let Some(aid) = arena_id(&entry.file_name().to_string_lossy()) {
+                aids.push(aid);
+            }
+        }
+        aids.sort_unstable();
+
+        let mut entries: Vec<Entry> = Vec::new();
+        for aid in aids {
+            let data = match fs::read(seek_index_path(&self.dir, aid)) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            for raw in data.chunks_exact(SEEK_ENTRY_SIZE) {
+                let entry = Entry {
+                    seq: transform_array_of_u8_to_u64(&raw[0..8]),
+                    bytes: transform_array_of_u8_to_u64(&raw[8..16]),
+                    pos: transform_array_of_u8_to_u64(&raw[16..24]),
+                    time: transform_array_of_u8_to_u64(&raw[24..32]),
+                };
+                // entries past the tail were cut off by a recovery
+                if entry.seq >= pushed || entry.pos >= tail {
+                    continue;
+                }
+                // and written again once the records were pushed again
+                while entries.last().is_some_and(|last| last.seq >= entry.seq) {
+                    entries.pop();
+                }
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Entry {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(SEEK_ENTRY_SIZE);
+        for word in [self.seq, self.bytes, self.pos, self.time] {
+            raw.extend_from_slice(&transform_u64_to_array_of_u8(word));
+        }
+        raw
+    }
+}
+
+/// Where the record written at `pos` starts if it is the first one in its
+/// arena or index step, and so gets an index entry.
+fn step_start(pos: u64, arena_size: u64) -> Option<u64> {
+    let start = header_pos(pos, arena_size);
+    let step = |p: u64| (p / arena_size, p % arena_size / SEEK_INDEX_STEP);
+    if pos > 0 && step(pos - 1) == step(start) {
+        return None;
+    }
+    Some(start)
+}
+
+pub(crate) fn seek_index_path(dir: &Path, aid: usize) -> PathBuf {
+    dir.join(format!("arena_{}.idx", aid))
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use crate::{BigQueue, Config, Error};
+
+    #[test]
+    fn test_seek() {
+        let dir = "/tmp/bigqueue_test_seek";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 256;
+        let mut q = BigQueue::with_config(dir, true, conf.clone()).unwrap();
+        for i in 0..20u8 {
+            q.push(&[i; 20]).unwrap();
+        }
+        thread::sleep(Duration::from_millis(20));
+        let later = SystemTime::now();
+        for i in 20..40u8 {
+            q.push(&[i; 20]).unwrap();
+        }
+        for _ in 0..30 {
+            q.pop().unwrap();
+        }
+
+        q.seek(3).unwrap();
+        assert_eq!((q.next_seq(), q.len()), (3, 37));
+        assert_eq!(q.pop().unwrap(), vec![3; 20]);
+        q.seek(35).unwrap();
+        assert_eq!(q.pop().unwrap(), vec![35; 20]);
+        q.seek(40).unwrap();
+        assert!(q.is_empty());
+        match q.seek(41) {
+            Err(Error::NotRetained(41)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let seq = q.seek_to_time(later).unwrap();
+        assert!(seq <= 20 && seq > 0);
+        assert_eq!(q.pop().unwrap(), vec![seq as u8; 20]);
+        drop(q);
+
+        // the head was persisted where it was moved, and consumed arenas go
+        let mut q = BigQueue::with_config(dir, false, conf).unwrap();
+        assert_eq!(q.next_seq(), seq + 1);
+        q.seek(39).unwrap();
+        q.shrink();
+        match q.seek(0) {
+            Err(Error::NotRetained(0)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_seek_without_index() {
+        let dir = "/tmp/bigqueue_test_seek_rebuild";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 256;
+        let mut q = BigQueue::with_config(dir, true, conf.clone()).unwrap();
+        for i in 0..40u8 {
+            q.push(&[i; 20]).unwrap();
+        }
+        for _ in 0..12 {
+            q.pop().unwrap();
+        }
+        drop(q);
+
+        // as left by a version that wrote no seek index
+        for entry in fs::read_dir(dir).unwrap().flatten() {
+            if entry.path().extension().is_some_and(|e| e == "idx") {
+                fs::remove_file(entry.path()).unwrap();
+            }
+        }
+
+        let mut q = BigQueue::with_config(dir, false, conf).unwrap();
+        q.seek(30).unwrap();
+        assert_eq!(q.pop().unwrap(), vec![30; 20]);
+        // records behind the head are found by walking its arena from the
+        // start, the arena before it went on drop
+        q.seek(9).unwrap();
+        assert_eq!(q.pop().unwrap(), vec![9; 20]);
+        match q.seek(8) {
+            Err(Error::NotRetained(8)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(fs::metadata(format!("{}/arena_1.idx", dir)).is_ok());
+        q.seek(39).unwrap();
+        assert_eq!(q.pop().unwrap(), vec![39; 20]);
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fs;
+
+use crate::{BigQueue, Error, Result};
+use crate::bigqueue::arena_id;
+
+/// A snapshot of a queue's size and files, see `BigQueue::stats`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    /// Records between head and tail.
+    pub len: u64,
+    /// Payload bytes of those records, without length headers and checksums.
+    pub byte_len: u64,
+    pub head_aid: usize,
+    pub tail_aid: usize,
+    /// `arena_<id>.dat` files in the queue directory, including arenas
+    /// consumed but not yet removed by `shrink`.
+    pub arena_files: usize,
+}
+
+impl BigQueue {
+    /// Number of records between head and tail. Records handed out by
+    /// `receive` are no longer counted, acknowledged or not.
+    pub fn len(&self) -> u64 {
+        let (pushed, _) = self.index.get_tail_counts();
+        pushed.saturating_sub(self.popped)
+    }
+
+    /// Payload bytes of the records counted by `len`.
+    pub fn byte_len(&self) -> u64 {
+        let (_, pushed_bytes) = self.index.get_tail_counts();
+        pushed_bytes.saturating_sub(self.popped_bytes)
+    }
+
+    /// Size of the queue and where its cursors are. A read-only handle
+    /// reloads the cursors first, see `refresh`.
+    pub fn stats(&mut self) -> Result<Stats> {
+        self.refresh()?;
+        let mut arena_files = 0;
+        for entry in fs::read_dir(&self.dir).map_err(Error::Io)?.flatten() {
+            if arena_id(&entry.file_name().to_string_lossy()).is_some() {
+                arena_files += 1;
+            }
+        }
+        Ok(Stats {
+            len: self.len(),
+            byte_len: self.byte_len(),
+            head_aid: self.head_aid,
+            tail_aid: self.tail_aid,
+            arena_files,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{BigQueue, Config};
+
+    #[test]
+    fn test_len_and_stats() {
+        let dir = "/tmp/bigqueue_test_stats";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        let mut q = BigQueue::with_config(dir, true, conf.clone()).unwrap();
+        assert_eq!((q.len(), q.byte_len()), (0, 0));
+
+        for i in 0..10u8 {
+            q.push(&[i; 20]).unwrap();
+        }
+        assert_eq!((q.len(), q.byte_len()), (10, 200));
+        q.pop().unwrap();
+        q.dequeue().unwrap();
+        q.pop_with(|_| ()).unwrap();
+        q.pop_ref().unwrap().commit().unwrap();
+        let delivery = q.receive().unwrap();
+        assert_eq!((q.len(), q.byte_len()), (5, 100));
+        drop(q);
+
+        // the unacknowledged delivery is not counted again after a reopen
+        let mut q = BigQueue::with_config(dir, false, conf).unwrap();
+        assert_eq!((q.len(), q.byte_len()), (5, 100));
+        let again = q.receive().unwrap();
+        assert_eq!(again.data(), delivery.data());
+        q.ack(&again).unwrap();
+        let stats = q.stats().unwrap();
+        assert_eq!((stats.len, stats.byte_len), (5, 100));
+        assert!(stats.head_aid <= stats.tail_aid);
+        assert!(stats.arena_files > stats.tail_aid - stats.head_aid);
+        q.shrink();
+        let stats = q.stats().unwrap();
+        assert_eq!(stats.arena_files, stats.tail_aid - stats.head_aid + 1);
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::io;
+use std::io::{ErrorKind, Read};
+
+use crate::{BigQueue, Error, Result};
+use crate::transform_u64_to_array_of_u8;
+
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The head record, read a chunk at a time. Returned by
+/// `BigQueue::pop_stream`. The record is popped once its last byte has
+/// been read and, with `Config::checksum`, verified; a reader dropped
+/// before that leaves it in the queue.
+pub struct RecordReader<'a> {
+    queue: &'a mut BigQueue,
+    length: u64,
+    // next payload byte to read
+    pos: u64,
+    remaining: u64,
+    crc: u32,
+    // first payload byte
+    start: u64,
+    done: bool,
+}
+
+impl BigQueue {
+    /// Append a record of `length` bytes read from `reader`, copying a
+    /// chunk at a time so the record never has to fit in memory. Returns
+    /// its sequence number, see `push`. If `reader` fails or ends early,
+    /// nothing is pushed.
+    pub fn push_stream<R: Read>(&mut self, length: u64, mut reader: R) -> Result<u64> {
+        self.check_writable()?;
+        let start = self.tail_pos();
+        let marks = self.marks.len();
+        self.mark(start);
+        let mut crc = crc32c::crc32c(&transform_u64_to_array_of_u8(length));
+        self.tail_offset = self.write_length(self.tail_offset, length);
+
+        let mut chunk = vec![0u8; STREAM_CHUNK_SIZE.min(length as usize)];
+        let mut remaining = length;
+        while remaining > 0 {
+            let want = chunk.len().min(remaining as usize);
+            let n = match reader.read(&mut chunk[..want]) {
+                Ok(0) => Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => Ok(n),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let written = match n {
+                Ok(n) => self.write_bytes(self.tail_offset, &chunk[..n]).map(|_| n),
+                Err(e) => Err(Error::Io(e)),
+            };
+            match written {
+                Ok(n) => {
+                    crc = crc32c::crc32c_append(crc, &chunk[..n]);
+                    remaining -= n as u64;
+                }
+                Err(e) => {
+                    self.marks.truncate(marks);
+                    self.seek_tail(start)?;
+                    return Err(e);
+                }
+            }
+        }
+        self.end_record(length as usize, crc)?;
+        self.commit_tail().map(|pushed| pushed - 1)
+    }
+
+    /// Pop the head record through a reader that copies it a chunk at a
+    /// time, see `RecordReader`.
+    pub fn pop_stream(&mut self) -> Result<RecordReader<'_>> {
+        self.check_writable()?;
+        if self.is_empty() {
+            return Err(Error::QueueEmpty);
+        }
+        let (start, length) = self.record_start(self.head_pos(), self.tail_pos())?;
+        let crc = crc32c::crc32c(&transform_u64_to_array_of_u8(length));
+        Ok(RecordReader { queue: self, length, pos: start, remaining: length, crc, start, done: false })
+    }
+}
+
+impl<'a> RecordReader<'a> {
+    /// Payload length of the record.
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Verify the checksum and move the head past the record.
+    fn finish(&mut self) -> Result<()> {
+        self.done = true;
+        self.queue.check_crc_at(self.start, self.length, self.crc)?;
+        let next = self.pos + self.queue.trailer_len() as u64;
+        self.queue.advance_head(next, self.length as usize)
+    }
+}
+
+impl<'a> Read for RecordReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+        let n = (buf.len() as u64).min(self.remaining) as usize;
+        self.queue.copy_at(self.pos, &mut buf[..n]).map_err(into_io)?;
+        self.crc = crc32c::crc32c_append(self.crc, &buf[..n]);
+        self.pos += n as u64;
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            self.finish().map_err(into_io)?;
+        }
+        Ok(n)
+    }
+}
+
+fn into_io(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => io::Error::new(ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+
+    use crate::{BigQueue, Config};
+
+    #[test]
+    fn test_push_and_pop_stream() {
+        let dir = "/tmp/bigqueue_test_stream";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        conf.checksum = true;
+        let mut q = BigQueue::with_config(dir, true, conf.clone()).unwrap();
+
+        let big: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        q.push(b"small").unwrap();
+        assert_eq!(q.push_stream(big.len() as u64, &big[..]).unwrap(), 1);
+        // a reader that ends early pushes nothing
+        assert!(q.push_stream(10, &b"short"[..]).is_err());
+        q.push_stream(0, &b""[..]).unwrap();
+        q.push(b"last").unwrap();
+        assert_eq!(q.len(), 4);
+
+        let mut data = Vec::new();
+        q.pop_stream().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"small".to_vec());
+
+        // half a record read stays in the queue
+        let mut half = vec![0u8; 1000];
+        q.pop_stream().unwrap().read_exact(&mut half).unwrap();
+        assert_eq!(q.len(), 3);
+        drop(q);
+
+        let mut q = BigQueue::with_config(dir, false, conf).unwrap();
+        let mut reader = q.pop_stream().unwrap();
+        assert_eq!(reader.len(), big.len() as u64);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, big);
+        assert!(q.pop_stream().unwrap().is_empty());
+        assert_eq!(q.pop().unwrap(), Vec::<u8>::new());
+        assert_eq!(q.pop().unwrap(), b"last".to_vec());
+    }
+}
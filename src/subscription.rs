@@ -0,0 +1,156 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::time::SystemTime;
+
+use crate::{BigQueue, Result};
+use crate::lock::DirLock;
+
+/// A named consumer with its own persisted cursor, opened by
+/// `BigQueue::subscribe`. Records pushed to the queue are seen by every
+/// subscription, and popping from one does not affect the others.
+pub struct Subscription {
+    name: String,
+    queue: BigQueue,
+    // released after the queue is dropped
+    _lock: DirLock,
+}
+
+impl Subscription {
+    pub(crate) fn new(name: &str, queue: BigQueue, lock: DirLock) -> Subscription {
+        Subscription { name: name.to_string(), queue, _lock: lock }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_empty(&mut self) -> bool {
+        self.queue.refresh_tail();
+        self.queue.is_empty()
+    }
+
+    /// Sequence number of the record the next `pop` returns.
+    pub fn next_seq(&self) -> u64 {
+        self.queue.next_seq()
+    }
+
+    pub fn peek(&mut self) -> Result<Vec<u8>> {
+        self.queue.refresh_tail();
+        self.queue.peek()
+    }
+
+    pub fn pop(&mut self) -> Result<Vec<u8>> {
+        self.queue.refresh_tail();
+        self.queue.pop()
+    }
+
+    pub fn pop_with<F, R>(&mut self, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> R {
+        self.queue.refresh_tail();
+        self.queue.pop_with(f)
+    }
+
+    pub fn dequeue(&mut self) -> Result<()> {
+        self.queue.refresh_tail();
+        self.queue.dequeue()
+    }
+
+    /// Move this subscription's cursor, see `BigQueue::seek`.
+    pub fn seek(&mut self, seq: u64) -> Result<()> {
+        self.queue.refresh_tail();
+        self.queue.seek(seq)
+    }
+
+    /// Move this subscription's cursor, see `BigQueue::seek_to_time`.
+    pub fn seek_to_time(&mut self, time: SystemTime) -> Result<u64> {
+        self.queue.refresh_tail();
+        self.queue.seek_to_time(time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::{BigQueue, Config, Error};
+
+    #[test]
+    fn test_fan_out() {
+        let dir = "/tmp/bigqueue_test_fan_out";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+
+        let mut audit = q.subscribe("audit").unwrap();
+        let mut indexer = q.subscribe("indexer").unwrap();
+        assert!(!Path::new(dir).join("audit.sub.tmp").exists());
+        // one handle per subscription
+        match q.subscribe("audit") {
+            Err(Error::Locked(_)) => {}
+            other => panic!("unexpected {:?}", other.map(|s| s.name().to_string())),
+        }
+        assert!(q.unsubscribe("indexer").is_err());
+        for i in 0..50u8 {
+            q.push(&[i; 10]).unwrap();
+        }
+        for i in 0..50u8 {
+            assert_eq!(audit.pop().unwrap(), vec![i; 10]);
+            assert_eq!(q.pop().unwrap(), vec![i; 10]);
+        }
+        assert!(audit.is_empty());
+        assert!(q.is_empty());
+
+        // the indexer has not read anything, so nothing can be deleted
+        q.shrink();
+        assert!(Path::new(dir).join("arena_0.dat").exists());
+        drop(audit);
+
+        // a reopened subscription resumes from its persisted cursor
+        for _ in 0..25 {
+            indexer.dequeue().unwrap();
+        }
+        drop(indexer);
+        let mut indexer = q.subscribe("indexer").unwrap();
+        assert_eq!(indexer.pop().unwrap(), vec![25; 10]);
+
+        drop(indexer);
+        q.unsubscribe("indexer").unwrap();
+        q.shrink();
+        assert!(!Path::new(dir).join("arena_0.dat").exists());
+        assert!(q.subscribe("../escape").is_err());
+    }
+
+    #[test]
+    fn test_shrink_follows_subscriptions() {
+        let dir = "/tmp/bigqueue_test_subscription_shrink";
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        let mut q = BigQueue::with_config(dir, true, conf).unwrap();
+
+        // the queue only produces, the subscriptions do all the reading
+        let mut audit = q.subscribe("audit").unwrap();
+        let mut indexer = q.subscribe("indexer").unwrap();
+        for i in 0..50u8 {
+            q.push(&[i; 10]).unwrap();
+        }
+        for _ in 0..50 {
+            audit.dequeue().unwrap();
+        }
+        for _ in 0..40 {
+            indexer.dequeue().unwrap();
+        }
+        q.shrink();
+        assert!(!Path::new(dir).join("arena_0.dat").exists());
+        assert_eq!(q.next_seq(), 40);
+        assert_eq!(q.pop().unwrap(), vec![40; 10]);
+        assert_eq!(indexer.pop().unwrap(), vec![40; 10]);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2019 bigqueue.rs Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{BigQueue, Result};
+
+/// How `TypedBigQueue` turns values into records and back. Failures are
+/// reported as `Error::Codec`.
+pub trait Codec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T>;
+}
+
+/// bincode, with its default options.
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| crate::Error::Codec(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+        bincode::deserialize(data).map_err(|e| crate::Error::Codec(e.to_string()))
+    }
+}
+
+/// JSON, one document per record.
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| crate::Error::Codec(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+        serde_json::from_slice(data).map_err(|e| crate::Error::Codec(e.to_string()))
+    }
+}
+
+/// MessagePack, structs encoded as maps so fields can be added later.
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(|e| crate::Error::Codec(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(data).map_err(|e| crate::Error::Codec(e.to_string()))
+    }
+}
+
+/// A `BigQueue` of `T` values, each stored as one record encoded with `C`.
+///
+/// ```no_run
+/// # #[cfg(feature = "json")]
+/// # fn main() -> Result<(), bigqueue::Error> {
+/// use bigqueue::{BigQueue, Json, TypedBigQueue};
+///
+/// let mut q: TypedBigQueue<(u32, String), Json> = TypedBigQueue::new(BigQueue::new("/tmp/typed", true)?);
+/// q.push(&(1, "one".to_string()))?;
+/// assert_eq!(q.pop()?, (1, "one".to_string()));
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "json"))]
+/// # fn main() {}
+/// ```
+pub struct TypedBigQueue<T, C> {
+    queue: BigQueue,
+    marker: PhantomData<fn(T, C) -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned, C: Codec> TypedBigQueue<T, C> {
+    pub fn new(queue: BigQueue) -> TypedBigQueue<T, C> {
+        TypedBigQueue { queue, marker: PhantomData }
+    }
+
+    /// Encode `value` and push it, returning its sequence number.
+    pub fn push(&mut self, value: &T) -> Result<u64> {
+        let data = C::encode(value)?;
+        self.queue.push(&data)
+    }
+
+    /// Pop and decode the head record. A record that fails to decode stays
+    /// at the head and every `pop` reports the same `Error::Codec`, until it
+    /// is inspected or skipped through the byte API, e.g.
+    /// `get_mut().dequeue()`.
+    pub fn pop(&mut self) -> Result<T> {
+        let value = C::decode(&self.queue.peek_ref()?)?;
+        self.queue.dequeue()?;
+        Ok(value)
+    }
+
+    pub fn peek(&mut self) -> Result<T> {
+        let data = self.queue.peek()?;
+        C::decode(&data)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.queue.len()
+    }
+
+    /// The underlying byte queue.
+    pub fn get_mut(&mut self) -> &mut BigQueue {
+        &mut self.queue
+    }
+
+    pub fn into_inner(self) -> BigQueue {
+        self.queue
+    }
+}
+
+#[cfg(all(test, any(feature = "bincode", feature = "json", feature = "msgpack")))]
+mod tests {
+    use std::fs;
+
+    use crate::{BigQueue, Config, Error};
+    use super::{Codec, TypedBigQueue};
+
+    type Item = (u32, String, Vec<u16>);
+
+    fn round_trip<C: Codec>(dir: &str) {
+        fs::create_dir_all(dir).expect("failed to create dir");
+        let mut conf = Config::new();
+        conf.arena_size = 64;
+        let queue = BigQueue::with_config(dir, true, conf).unwrap();
+        let mut q: TypedBigQueue<Item, C> = TypedBigQueue::new(queue);
+
+        let items: Vec<Item> = (0..20).map(|i| (i, format!("item {}", i), vec![i as u16; i as usize])).collect();
+        for (seq, item) in items.iter().enumerate() {
+            assert_eq!(q.push(item).unwrap(), seq as u64);
+        }
+        assert_eq!(q.peek().unwrap(), items[0]);
+        for item in &items {
+            assert_eq!(&q.pop().unwrap(), item);
+        }
+
+        // garbage pushed through the byte API is reported and left in place
+        q.get_mut().push(&[0xc1, 0xff, 0xff]).unwrap();
+        q.get_mut().push(b"").unwrap();
+        q.push(&items[0]).unwrap();
+        for garbage in [&[0xc1, 0xff, 0xff][..], b""] {
+            for _ in 0..2 {
+                match q.pop() {
+                    Err(Error::Codec(_)) => {}
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            assert_eq!(q.get_mut().pop().unwrap(), garbage.to_vec());
+        }
+        assert_eq!(q.pop().unwrap(), items[0]);
+        assert!(q.is_empty());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode() {
+        round_trip::<super::Bincode>("/tmp/bigqueue_test_typed_bincode");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        round_trip::<super::Json>("/tmp/bigqueue_test_typed_json");
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack() {
+        round_trip::<super::MessagePack>("/tmp/bigqueue_test_typed_msgpack");
+    }
+}